@@ -1,31 +1,110 @@
+mod host;
 mod pam;
 
-use std::process::Command;
+use std::{
+    fmt::Display,
+    path::{Path, PathBuf},
+};
 
 use anyhow::Result;
-use nix::unistd::{Group, User, gethostname};
+use glob::MatchOptions;
+use ipnet::IpNet;
+use nix::unistd::{Uid, User};
+use regex::Regex;
 use serde::{Deserialize, Serialize};
 
 use crate::{
-    authenticate::pam::{AuthErrorKind, authenticate_user},
-    config::Config,
-    run::Run,
+    authenticate::{
+        host::{Host, HostValue},
+        pam::{AuthErrorKind, authenticate_user},
+    },
+    backend::Backend,
+    config::{Aliases, Config, check::Diagnostic},
+    run::{
+        Run,
+        env::EnvPolicy,
+        path::{DEFAULT_SAFE_PATH, locate_command},
+    },
 };
 
-/// ActionValue represents a value within [Action]. It can either be Any, or a specific Value.
-#[derive(Debug, Clone, Default, PartialEq, Eq)]
+/// The command used in a [Rule] to allow editing files with udoedit
+pub const EDIT_COMMAND: &str = "udoedit";
+
+const GLOB_OPTIONS: MatchOptions = MatchOptions {
+    case_sensitive: true,
+    // Stops `/usr/local/bin/*` from also matching commands in subdirectories
+    require_literal_separator: true,
+    require_literal_leading_dot: false,
+};
+
+/// ActionValue represents a value within [Action]. It can either be Any, a specific Value, or a
+/// pattern matching several values.
+///
+/// In a [Rule], `ALL` is Any, values wrapped in `^` and `$` are regexes, values containing any of
+/// `*?[` are shell-style globs, and everything else is a specific value.
+#[derive(Debug, Clone, Default)]
 pub enum ActionValue {
     #[default]
     Any,
     Value(String),
+    Glob(glob::Pattern),
+    Regex(Regex),
+}
+
+impl ActionValue {
+    /// Creates the [ActionValue] of a command in a [Rule], locating it against `path` so it can
+    /// be compared to the command being run. Its directory is resolved, but if the command itself
+    /// is a symlink it isn't followed, as multi-call binaries behave differently depending on the
+    /// name they're run as
+    fn from_command(command: &str, path: &str) -> Self {
+        // udoedit isn't run, so it isn't resolved
+        if command == EDIT_COMMAND {
+            return Self::Value(command.to_string());
+        }
+
+        match Self::from(command) {
+            Self::Value(v) => match locate_command(&v, path) {
+                Some(p) => Self::Value(p.to_string_lossy().to_string()),
+                None => Self::Value(v),
+            },
+            other => other,
+        }
+    }
+
+    /// Parses a value from a [Rule], failing if it is an invalid pattern
+    pub fn parse(value: &str) -> std::result::Result<Self, String> {
+        if value == "ALL" {
+            Ok(Self::Any)
+        } else if value.len() > 1 && value.starts_with('^') && value.ends_with('$') {
+            // The anchors are applied to the whole pattern, otherwise `^a|b$` would match
+            // anything containing a or b
+            Regex::new(&format!("^(?:{})$", &value[1..value.len() - 1]))
+                .map(Self::Regex)
+                .map_err(|e| format!("invalid regex {value} ({e})"))
+        } else if value.contains(['*', '?', '[']) {
+            glob::Pattern::new(value)
+                .map(Self::Glob)
+                .map_err(|e| format!("invalid glob {value} ({e})"))
+        } else {
+            Ok(Self::Value(value.to_string()))
+        }
+    }
+
+    /// Checks if the given value is matched by this [ActionValue]
+    pub fn matches(&self, value: &str) -> bool {
+        match self {
+            Self::Any => true,
+            Self::Value(v) => v == value,
+            Self::Glob(p) => p.matches_with(value, GLOB_OPTIONS),
+            Self::Regex(r) => r.is_match(value),
+        }
+    }
 }
 
 impl From<String> for ActionValue {
+    // Invalid patterns are treated as literal values, so they only match themselves
     fn from(value: String) -> Self {
-        match value.as_str() {
-            "ALL" => Self::Any,
-            _ => Self::Value(value),
-        }
+        Self::parse(&value).unwrap_or(Self::Value(value))
     }
 }
 
@@ -41,67 +120,210 @@ impl From<&str> for ActionValue {
     }
 }
 
+/// ArgsValue represents the arguments a command within [Action] is run with.
+///
+/// In a [Rule], the arguments are given after the command, like sudoers:
+/// - `systemctl` allows any arguments
+/// - `systemctl restart nginx` allows exactly `restart nginx`
+/// - `systemctl restart nginx*` allows `restart` followed by one argument starting with `nginx`
+/// - `passwd ""` allows no arguments at all
+///
+/// The arguments of `udoedit` are instead the files which may be edited, which can be globs.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum ArgsValue {
+    #[default]
+    Any,
+    Exact(Vec<String>),
+    /// Exactly the arguments in the first value, followed by one argument starting with the second
+    Prefix(Vec<String>, String),
+    Files(Vec<glob::Pattern>),
+}
+
+impl ArgsValue {
+    /// Parses the arguments following the command in a [Rule]
+    fn from_rule_args(args: &[&str]) -> Self {
+        match args {
+            [] => Self::Any,
+            [r#""""#] => Self::Exact(Vec::new()),
+            [rest @ .., last] if last.ends_with('*') => Self::Prefix(
+                rest.iter().map(|a| a.to_string()).collect(),
+                last.trim_end_matches('*').to_string(),
+            ),
+            _ => Self::Exact(args.iter().map(|a| a.to_string()).collect()),
+        }
+    }
+
+    /// Parses the files following `udoedit` in a [Rule]
+    fn from_edit_args(args: &[&str]) -> Self {
+        if args.is_empty() {
+            return Self::Any;
+        }
+
+        // Invalid globs are treated as literal paths, so they only match themselves
+        Self::Files(
+            args.iter()
+                .map(|a| {
+                    glob::Pattern::new(a)
+                        .or_else(|_| glob::Pattern::new(&glob::Pattern::escape(a)))
+                        .unwrap_or_default()
+                })
+                .collect(),
+        )
+    }
+
+    pub fn contains(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Self::Any, _) => true,
+            (Self::Exact(a), Self::Exact(b)) => a == b,
+            // The prefix only applies to the last argument, so it can't match extra arguments
+            (Self::Prefix(fixed, prefix), Self::Exact(b)) => match b.split_last() {
+                Some((last, rest)) => rest == fixed.as_slice() && last.starts_with(prefix.as_str()),
+                None => false,
+            },
+            (Self::Files(f), Self::Exact(b)) => {
+                !b.is_empty()
+                    && b.iter()
+                        .all(|b| f.iter().any(|f| f.matches_with(b, GLOB_OPTIONS)))
+            }
+            // The action being attempted always has exact arguments
+            _ => false,
+        }
+    }
+}
+
+/// RunAs represents the users an [Action] can be run as
+#[derive(Debug, Clone, Default)]
+pub enum RunAs {
+    /// Any user, written `ALL` in a [Rule]
+    #[default]
+    Any,
+    /// Users whose name matches the value
+    User(ActionValue),
+    /// The user with a uid, written `#uid` in a [Rule]
+    Uid(Uid),
+    /// Members of groups whose name matches the value, written `%group` in a [Rule]
+    Member(ActionValue),
+}
+
+impl From<&String> for RunAs {
+    fn from(value: &String) -> Self {
+        if value == "ALL" {
+            return Self::Any;
+        }
+        if let Some(uid) = parse_uid(value) {
+            return Self::Uid(uid);
+        }
+
+        match value.strip_prefix("%") {
+            Some(group) => Self::Member(group.into()),
+            None => Self::User(value.into()),
+        }
+    }
+}
+
+/// Parses a user written as `#uid`, returning [None] if it isn't one
+pub fn parse_uid(value: &str) -> Option<Uid> {
+    value.strip_prefix('#')?.parse().ok().map(Uid::from_raw)
+}
+
 /// Action is the internal representation of a [Rule]. It represents the commands the user is
-/// allowed to run, the hostname they can run them as, and the user they can run them as
+/// allowed to run, the arguments they can run them with, the hostname they can run them as, and
+/// the user and group they can run them as
 ///
-/// It is composed of [ActionValue]
+/// It is composed of [ActionValue] and [ArgsValue]
 #[derive(Debug, Clone, Default)]
 pub struct Action {
     pub command: ActionValue,
-    pub host: Option<ActionValue>,
-    pub do_as: ActionValue,
+    /// The path the command was found at before resolving symlinks. Commands in rules must match
+    /// it exactly, and patterns may match either it or the canonical path. Only used in the action
+    /// being attempted
+    pub command_path: Option<String>,
+    pub args: ArgsValue,
+    pub host: HostValue,
+    /// The host the action is attempted on, or [None] if it couldn't be identified. Only used in
+    /// the action being attempted
+    pub current_host: Option<Host>,
+    pub do_as: RunAs,
+    /// The uid of the user being run as. Only used in the action being attempted
+    pub do_as_uid: Option<Uid>,
+    /// The names of the primary and supplementary groups of the user being run as. Only used in
+    /// the action being attempted
+    pub do_as_groups: Vec<String>,
+    /// The primary group to run as. [None] means the target user's own primary group
+    pub group: Option<ActionValue>,
 }
 
 impl Action {
-    fn from_rule(rule: &Rule) -> Self {
+    /// Creates an action from a [Rule], resolving its command against `path`
+    fn from_rule(rule: &Rule, path: &str) -> Self {
+        let mut parts = rule.command.split_whitespace();
+        let command = parts.next().unwrap_or_default();
+        let args = parts.collect::<Vec<_>>();
+
+        let args = match command {
+            EDIT_COMMAND => ArgsValue::from_edit_args(&args),
+            _ => ArgsValue::from_rule_args(&args),
+        };
+
         Self {
-            command: (&rule.command).into(),
-            host: Some((&rule.host).into()),
+            command: ActionValue::from_command(command, path),
+            command_path: None,
+            args,
+            host: (&rule.host).into(),
+            current_host: None,
             do_as: (&rule.user).into(),
+            do_as_uid: None,
+            do_as_groups: Vec::new(),
+            group: rule.group.as_ref().map(ActionValue::from),
         }
     }
 
     pub fn contains(&self, other: &Self) -> bool {
-        let cmd = match &self.command {
-            ActionValue::Any => true,
-            ActionValue::Value(v) => {
-                let v = v.clone();
-                other.command == ActionValue::Value(v)
+        let cmd = match (&self.command, &other.command) {
+            (ActionValue::Any, _) => true,
+            // The command must be found at the same path as in the rule, not just resolve to the
+            // same executable, so `/usr/bin/rbash` doesn't allow running `/usr/bin/bash`
+            (ActionValue::Value(v), ActionValue::Value(o)) => {
+                let path = other.command_path.as_deref().unwrap_or(o);
+                Path::new(v) == Path::new(path) && self.args.contains(&other.args)
             }
+            // Patterns can't be resolved, so a pattern like `/usr/local/bin/*` also matches the
+            // symlinks in that directory
+            (pattern, ActionValue::Value(o)) => {
+                let path_matches = other
+                    .command_path
+                    .as_ref()
+                    .is_some_and(|p| pattern.matches(p));
+                (pattern.matches(o) || path_matches) && self.args.contains(&other.args)
+            }
+            _ => false,
         };
 
-        // Because getting the hostname is a fallible operation, we support cases where we couldn't get the hostname
-        let host;
-        // If we couldn't get the hostname then
-        if other.host.is_none() {
-            host = match self.host {
-                // If this hostname is any, we allow it
-                Some(ActionValue::Any) => true,
-                // Otherwise we don't
-                None | Some(ActionValue::Value(_)) => false,
-            }
-        } else {
-            // If we could get the hostname then
-            let h = other.host.as_ref().unwrap();
-            host = match &self.host {
-                // Check if this action allows any
-                Some(ActionValue::Any) => true,
-                // Check if this action's hostname allows others
-                Some(h) => true,
-                // If this action has no hostname (shouldn't happen!) don't allow
-                None => false,
-            };
-        }
+        // Because getting the hostname is a fallible operation, we support cases where we couldn't
+        // get the hostname by only allowing actions on any host
+        let host = match &other.current_host {
+            Some(h) => h.matches(&self.host),
+            None => matches!(self.host, HostValue::Name(ActionValue::Any)),
+        };
 
-        let run_as = match &self.do_as {
-            ActionValue::Any => true,
-            ActionValue::Value(v) => {
-                let v = v.clone();
-                matches!(&other.do_as, ActionValue::Value(v))
+        let run_as = match (&self.do_as, &other.do_as) {
+            (RunAs::Any, RunAs::User(ActionValue::Value(_))) => true,
+            (RunAs::User(u), RunAs::User(ActionValue::Value(v))) => u.matches(v),
+            (RunAs::Uid(uid), RunAs::User(ActionValue::Value(_))) => other.do_as_uid == Some(*uid),
+            (RunAs::Member(g), RunAs::User(ActionValue::Value(_))) => {
+                other.do_as_groups.iter().any(|o| g.matches(o))
             }
+            _ => false,
+        };
+
+        let group = match (&self.group, &other.group) {
+            // Running as the target user's own primary group is always allowed
+            (_, None) => true,
+            (Some(g), Some(ActionValue::Value(o))) => g.matches(o),
+            _ => false,
         };
 
-        cmd && host && run_as
+        cmd && host && run_as && group
     }
 }
 
@@ -112,47 +334,281 @@ pub enum AuthResult {
 }
 
 /// Rule is used in the configuration file, which is why it is a distinct type from [Action].
+///
+/// Rules are evaluated in the order they appear in the config, and the last rule matching an
+/// action decides if it's allowed. A rule with `deny = true` forbids the actions it matches.
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Rule {
     target: String,
     host: String,
     user: String,
     command: String,
+    /// The primary group the rule allows running as with `--group`
+    #[serde(default)]
+    group: Option<String>,
+    #[serde(default)]
+    deny: bool,
+    /// Allows the actions the rule matches without authenticating
+    #[serde(default)]
+    nopass: bool,
+    #[serde(flatten)]
+    env: EnvPolicy,
+    /// The drop-in file the rule was read from and its position in it, or [None] if it's in the
+    /// main config
+    #[serde(skip)]
+    fragment: Option<(PathBuf, usize)>,
+}
+
+impl Display for Rule {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let deny = if self.deny { "!" } else { "" };
+        let nopass = if self.nopass { "NOPASSWD: " } else { "" };
+        let group = match &self.group {
+            Some(g) => format!(":{g}"),
+            None => String::new(),
+        };
+        write!(
+            f,
+            "{} {}=({}{group}) {nopass}{deny}{}",
+            self.target, self.host, self.user, self.command
+        )
+    }
 }
 
 impl Rule {
+    #[cfg(test)]
     pub fn new(target: String, host: String, user: String, command: String) -> Self {
         Self {
             target,
             host,
             user,
             command,
+            group: None,
+            deny: false,
+            nopass: false,
+            env: EnvPolicy::default(),
+            fragment: None,
         }
     }
 
-    /// Checks if the rule applies to the current user
-    pub fn applies_to(&self, user: &User) -> Result<bool> {
-        if self.target == user.name {
-            return Ok(true);
+    #[cfg(test)]
+    pub fn with_group(mut self, group: String) -> Self {
+        self.group = Some(group);
+        self
+    }
+
+    #[cfg(test)]
+    pub fn with_deny(mut self) -> Self {
+        self.deny = true;
+        self
+    }
+
+    #[cfg(test)]
+    pub fn with_nopass(mut self) -> Self {
+        self.nopass = true;
+        self
+    }
+
+    /// Records that the rule was read from the drop-in file `path`, at position `index` in it
+    pub fn with_fragment(mut self, path: &Path, index: usize) -> Self {
+        self.fragment = Some((path.to_path_buf(), index));
+        self
+    }
+
+    /// Describes where the rule is, given its position `i` among all the rules, like `rule 3` or
+    /// `rule 1 of /etc/udo/config.d/10-web.toml`
+    pub fn position(&self, i: usize) -> String {
+        match &self.fragment {
+            Some((path, index)) => format!("rule {} of {}", index + 1, path.display()),
+            None => format!("rule {}", i + 1),
         }
+    }
 
-        if self.target.starts_with("%")
-            && let Some(group) = Group::from_name(&self.target[1..])?
-        {
-            if group.gid == user.gid {
-                return Ok(true);
-            }
-            let supp_groups = get_supplemental_groups(user)?
-                .iter()
-                .map(|g| g.gid)
-                .collect::<Vec<_>>();
-            if supp_groups.contains(&group.gid) {
+    /// Checks if the rule applies to the current user
+    pub fn applies_to(
+        &self,
+        user: &User,
+        aliases: &Aliases,
+        backend: &dyn Backend,
+    ) -> Result<bool> {
+        for target in aliases.users(&self.target) {
+            let applies = match (target.strip_prefix("%"), parse_uid(target)) {
+                (Some(group), _) => {
+                    let group = ActionValue::from(group);
+                    get_group_names(user, backend)?
+                        .iter()
+                        .any(|g| group.matches(g))
+                }
+                (None, Some(uid)) => user.uid == uid,
+                (None, None) => ActionValue::from(target).matches(&user.name),
+            };
+            if applies {
                 return Ok(true);
             }
         }
 
         Ok(false)
     }
+
+    /// Expands the aliases the rule uses, returning a rule for each combination of their values
+    pub fn expand(&self, aliases: &Aliases) -> Vec<Rule> {
+        let mut rules = Vec::new();
+        for target in aliases.users(&self.target) {
+            for host in aliases.hosts(&self.host) {
+                for user in aliases.users(&self.user) {
+                    for command in aliases.commands(&self.command) {
+                        rules.push(Rule {
+                            target: target.clone(),
+                            host: host.clone(),
+                            user: user.clone(),
+                            command: command.clone(),
+                            ..self.clone()
+                        });
+                    }
+                }
+            }
+        }
+
+        rules
+    }
+
+    /// Checks the rule for mistakes, resolving its command against `path` and looking up users
+    /// and groups with `backend`
+    pub fn validate(&self, path: &str, backend: &dyn Backend) -> Vec<Diagnostic> {
+        let mut diagnostics = Vec::new();
+        for (field, value) in [("target", &self.target), ("user", &self.user)] {
+            match value.strip_prefix("%") {
+                Some(group) => check_name(field, group, true, backend, &mut diagnostics),
+                None => check_name(field, value, false, backend, &mut diagnostics),
+            }
+        }
+        if let Some(group) = &self.group {
+            check_name("group", group, true, backend, &mut diagnostics);
+        }
+        if let Err(e) = ActionValue::parse(&self.host) {
+            diagnostics.push(Diagnostic::error(format!("host is an {e}")));
+        }
+        // Hostnames can't contain slashes, so this was meant to be a CIDR range
+        if self.host.contains('/') && self.host.parse::<IpNet>().is_err() {
+            diagnostics.push(Diagnostic::error(format!(
+                "host is an invalid network {}",
+                self.host
+            )));
+        }
+
+        match self.command.split_whitespace().next() {
+            None => diagnostics.push(Diagnostic::error("command is empty")),
+            Some(command) => match ActionValue::parse(command) {
+                Ok(ActionValue::Value(v))
+                    if v != EDIT_COMMAND && locate_command(&v, path).is_none() =>
+                {
+                    diagnostics.push(Diagnostic::warning(format!(
+                        "command {v} wasn't found in the safe path"
+                    )));
+                }
+                Ok(_) => {}
+                Err(e) => diagnostics.push(Diagnostic::error(format!("command is an {e}"))),
+            },
+        }
+        let mut parts = self.command.split_whitespace();
+        if parts.next() == Some(EDIT_COMMAND) {
+            for file in parts.filter(|f| glob::Pattern::new(f).is_err()) {
+                diagnostics.push(Diagnostic::error(format!("file {file} is an invalid glob")));
+            }
+        }
+
+        if self.deny && self.nopass {
+            diagnostics.push(Diagnostic::warning("nopass has no effect on a deny rule"));
+        }
+
+        diagnostics
+    }
+
+    /// Checks if this rule matches every action `other` matches, so that `other` is never used if
+    /// it comes before this rule
+    pub fn overrides(&self, other: &Rule) -> bool {
+        let covers = |a: &str, b: &str| a == "ALL" || a == b;
+
+        let group = match (&self.group, &other.group) {
+            (_, None) => true,
+            (Some(a), Some(b)) => covers(a, b),
+            (None, Some(_)) => false,
+        };
+
+        let mut parts = self.command.split_whitespace();
+        let mut other_parts = other.command.split_whitespace();
+        let command = match (parts.next(), other_parts.next()) {
+            (Some("ALL"), _) => true,
+            // A command without arguments allows any arguments
+            (Some(a), Some(b)) if a == b => {
+                let args = parts.collect::<Vec<_>>();
+                args.is_empty() || args == other_parts.collect::<Vec<_>>()
+            }
+            _ => false,
+        };
+
+        covers(&self.target, &other.target)
+            && covers(&self.host, &other.host)
+            && covers(&self.user, &other.user)
+            && group
+            && command
+    }
+}
+
+/// Checks that a user or group name in a [Rule] is a valid pattern, and that it exists if it's a
+/// specific name
+fn check_name(
+    field: &str,
+    value: &str,
+    group: bool,
+    backend: &dyn Backend,
+    diagnostics: &mut Vec<Diagnostic>,
+) {
+    if !group && value.starts_with('#') {
+        match parse_uid(value) {
+            Some(uid) if backend.user_by_uid(uid).is_ok_and(|u| u.is_none()) => diagnostics.push(
+                Diagnostic::warning(format!("{field} is an unknown user {value}")),
+            ),
+            Some(_) => {}
+            None => diagnostics.push(Diagnostic::error(format!(
+                "{field} is an invalid uid {value}"
+            ))),
+        }
+        return;
+    }
+
+    match ActionValue::parse(value) {
+        Ok(ActionValue::Value(v)) => {
+            let exists = match group {
+                true => backend.group_by_name(&v).is_ok_and(|g| g.is_some()),
+                false => backend.user_by_name(&v).is_ok_and(|u| u.is_some()),
+            };
+            if !exists {
+                let kind = if group { "group" } else { "user" };
+                diagnostics.push(Diagnostic::warning(format!(
+                    "{field} is an unknown {kind} {v}"
+                )));
+            }
+        }
+        Ok(_) => {}
+        Err(e) => diagnostics.push(Diagnostic::error(format!("{field} is an {e}"))),
+    }
+}
+
+/// Get the names of the primary and supplementary groups of a user
+fn get_group_names(user: &User, backend: &dyn Backend) -> Result<Vec<String>> {
+    let mut names = Vec::new();
+    if let Some(primary) = backend.group_by_gid(user.gid)? {
+        names.push(primary.name);
+    }
+    names.extend(
+        backend
+            .supplementary_groups(user)?
+            .into_iter()
+            .map(|g| g.name),
+    );
+
+    Ok(names)
 }
 
 /// Attempts to authenticate the user with the given password
@@ -170,69 +626,740 @@ pub fn authenticate_password(run: &Run, config: &Config, password: String) -> Au
     }
 }
 
+/// Decision is the outcome of checking an action against the [Rule]s in the config
+#[derive(Debug, Clone, Default)]
+pub struct Decision {
+    /// The rule which decided the outcome and its position in the config, or [None] if no rule
+    /// matched the action
+    pub rule: Option<(usize, Rule)>,
+    /// Why the rules couldn't be checked, in which case the action is never allowed
+    pub error: Option<String>,
+}
+
+impl Decision {
+    /// Creates a decision refusing an action because the rules couldn't be checked. Skipping the
+    /// rules which can't be checked could skip a deny rule, so nothing is allowed instead
+    fn failed(error: impl Display) -> Self {
+        Self {
+            rule: None,
+            error: Some(error.to_string()),
+        }
+    }
+
+    pub fn allowed(&self) -> bool {
+        self.error.is_none() && self.rule.as_ref().is_some_and(|(_, r)| !r.deny)
+    }
+
+    /// Returns if the action is allowed without authenticating
+    pub fn nopass(&self) -> bool {
+        self.allowed() && self.rule.as_ref().is_some_and(|(_, r)| r.nopass)
+    }
+
+    /// Returns the environment policy of the deciding rule
+    pub fn env_policy(&self) -> EnvPolicy {
+        match &self.rule {
+            Some((_, r)) if self.allowed() => r.env.clone(),
+            _ => EnvPolicy::default(),
+        }
+    }
+}
+
+impl Display for Decision {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if let Some(e) = &self.error {
+            return write!(f, "not allowed, as the rules couldn't be checked ({e})");
+        }
+        match &self.rule {
+            Some((i, rule)) if rule.deny => write!(f, "denied by {} ({rule})", rule.position(*i)),
+            Some((i, rule)) => write!(f, "allowed by {} ({rule})", rule.position(*i)),
+            None => f.write_str("not allowed by any rule"),
+        }
+    }
+}
+
 /// Check if the user is allowed to run the action they are trying to
 ///
-/// If the hostname cannot be retrieved, it will allow the action only if
+/// The last [Rule] matching the action decides the outcome, so later rules override earlier
+/// ones. If the hostname cannot be retrieved, it will allow the action only if
 /// there is a [Rule] with hostname ANY
-pub fn check_action_auth(run: &Run, config: &Config) -> bool {
+pub fn check_action_auth(run: &Run, config: &Config) -> Decision {
+    let command = run.command.as_ref().unwrap();
+    let executable = run.executable.as_ref().unwrap();
+
+    check_auth(
+        run,
+        config,
+        ActionValue::Value(executable.to_string_lossy().to_string()),
+        run.command_path
+            .as_ref()
+            .map(|p| p.to_string_lossy().to_string()),
+        ArgsValue::Exact(command[1..].to_vec()),
+    )
+}
+
+/// Check if the user is allowed to edit a file with udoedit.
+///
+/// Editing is authorised by rules with the command `udoedit`, with the files which may be edited
+/// as its arguments, e.g. `udoedit /etc/nginx/*`
+pub fn check_edit_auth(run: &Run, config: &Config, file: &Path) -> Decision {
+    check_auth(
+        run,
+        config,
+        ActionValue::Value(EDIT_COMMAND.to_string()),
+        None,
+        ArgsValue::Exact(vec![file.to_string_lossy().to_string()]),
+    )
+}
+
+/// Check if the user may refresh their cached login with `-v`.
+///
+/// Like `sudo -v`, it's only allowed if a rule allows the user to do something on this host. Rules
+/// requiring a password are preferred, so that refreshing the login caches it
+pub fn check_validate_auth(run: &Run, config: &Config) -> Decision {
+    let rules = match get_matching_rules(&run.user, config, run.backend.as_ref()) {
+        Ok(r) => r,
+        Err(e) => return Decision::failed(e),
+    };
+    let host = Host::current(config.security.fqdn);
+
+    let allowing = rules
+        .into_iter()
+        .filter(|(_, r)| !r.deny)
+        .filter(|(_, r)| {
+            r.expand(&config.aliases).iter().any(|r| {
+                let value = HostValue::from(&r.host);
+                match &host {
+                    Some(h) => h.matches(&value),
+                    None => matches!(value, HostValue::Name(ActionValue::Any)),
+                }
+            })
+        })
+        .collect::<Vec<_>>();
+    let rule = allowing
+        .iter()
+        .rev()
+        .find(|(_, r)| !r.nopass)
+        .or(allowing.last())
+        .cloned();
+
+    Decision { rule, error: None }
+}
+
+fn check_auth(
+    run: &Run,
+    config: &Config,
+    command: ActionValue,
+    command_path: Option<String>,
+    args: ArgsValue,
+) -> Decision {
     // Get the rules the user is authorised to run
-    let applicable_rules = get_matching_rules(&run.user, config);
-    let allowed_actions = applicable_rules
+    let applicable_rules = match get_matching_rules(&run.user, config, run.backend.as_ref()) {
+        Ok(r) => r,
+        Err(e) => return Decision::failed(e),
+    };
+    let path = config
+        .security
+        .safe_path
+        .as_deref()
+        .unwrap_or(DEFAULT_SAFE_PATH);
+    // A rule using aliases allows an action for each combination of their values
+    let (applicable_rules, allowed_actions): (Vec<_>, Vec<_>) = applicable_rules
         .iter()
-        .map(Action::from_rule)
-        .collect::<Vec<_>>();
+        .flat_map(|rule| {
+            rule.1
+                .expand(&config.aliases)
+                .into_iter()
+                .map(|r| (rule.clone(), Action::from_rule(&r, path)))
+        })
+        .unzip();
 
-    // Get the current hostname. If we can't get it, only allow the action to proceed if there is
-    // an allowed action with hostname rule any
-    let hostname = gethostname().ok();
+    // Identify the current host. If we can't, only allow the action to proceed if there is an
+    // allowed action with hostname rule any
+    let host = Host::current(config.security.fqdn);
 
-    if hostname.is_none()
+    if host.is_none()
         && allowed_actions
             .iter()
-            .any(|a| !matches!(a.host, Some(ActionValue::Any)))
+            .any(|a| !matches!(a.host, HostValue::Name(ActionValue::Any)))
     {
-        return false;
+        return Decision::default();
     }
 
+    // Run as rules can target groups, so they can't be checked without them either
+    let do_as_groups = match get_group_names(&run.do_as, run.backend.as_ref()) {
+        Ok(g) => g,
+        Err(e) => return Decision::failed(e),
+    };
+
     // Create the action of what the user is trying to do
     let action = Action {
-        command: ActionValue::from(&run.command.as_ref().unwrap()[0]),
-        host: hostname.map(|h| h.to_string_lossy().to_string().into()),
-        do_as: ActionValue::from(run.do_as.name.clone()),
+        command,
+        command_path,
+        args,
+        host: HostValue::default(),
+        current_host: host,
+        do_as: RunAs::User(ActionValue::Value(run.do_as.name.clone())),
+        do_as_uid: Some(run.do_as.uid),
+        do_as_groups,
+        group: run
+            .group
+            .as_ref()
+            .filter(|g| g.gid != run.do_as.gid)
+            .map(|g| ActionValue::Value(g.name.clone())),
     };
 
-    // Filter the allowed actions for ones which contain the action the user is attempting
-    let matching_actions = allowed_actions
-        .iter()
-        .filter(|a| a.contains(&action))
-        .collect::<Vec<_>>();
-
-    !matching_actions.is_empty()
+    decide(&applicable_rules, &allowed_actions, &action)
 }
 
-/// Get the rules which apply to the current user
-fn get_matching_rules(user: &User, config: &Config) -> Vec<Rule> {
-    config
-        .rules
+/// Finds the last of the rules whose [Action] contains the action the user is attempting
+fn decide(rules: &[(usize, Rule)], allowed_actions: &[Action], action: &Action) -> Decision {
+    let rule = rules
         .iter()
-        .filter(|&r| r.applies_to(user).is_ok_and(|v| v))
-        .cloned()
-        .collect()
+        .zip(allowed_actions)
+        .rev()
+        .find(|(_, a)| a.contains(action))
+        .map(|(r, _)| r.clone());
+
+    Decision { rule, error: None }
 }
 
-#[cfg(target_os = "macos")]
-fn get_supplemental_groups(user: &User) -> Result<Vec<Group>> {
-    let output = Command::new("id").args(["-Gn", &user.name]).output()?;
-    Ok(String::from_utf8(output.stdout)?
-        .split(' ')
-        .flat_map(Group::from_name)
-        .flatten()
-        .collect::<Vec<_>>())
+/// Get the rules which apply to the current user, along with their position in the config. Fails
+/// if any rule can't be checked, e.g. because its groups couldn't be looked up
+pub fn get_matching_rules(
+    user: &User,
+    config: &Config,
+    backend: &dyn Backend,
+) -> Result<Vec<(usize, Rule)>> {
+    let mut rules = Vec::new();
+    for (i, rule) in config.rules.iter().enumerate() {
+        if rule.applies_to(user, &config.aliases, backend)? {
+            rules.push((i, rule.clone()));
+        }
+    }
+
+    Ok(rules)
 }
 
-#[cfg(target_os = "linux")]
-fn get_supplemental_groups(user: &User) -> Result<Vec<Gid>> {
-    use nix::unistd::getgroups;
+#[cfg(test)]
+mod tests {
+    use std::collections::{BTreeMap, HashSet};
+
+    use nix::unistd::{Uid, User};
 
-    Ok(getgroups().iter().flat_map(Group::from_gid).flatten())
+    use crate::{
+        authenticate::{
+            Action, ActionValue, ArgsValue, Rule, RunAs, check_action_auth, check_validate_auth,
+            decide,
+            host::{Host, HostValue},
+        },
+        backend::testing::{TestBackend, test_group, test_user},
+        config::{Aliases, Config},
+        run::{
+            Run,
+            env::EnvPolicy,
+            path::{DEFAULT_SAFE_PATH, locate_command, resolve_command},
+        },
+    };
+
+    fn config(rules: Vec<Rule>) -> Config {
+        Config {
+            rules,
+            ..Default::default()
+        }
+    }
+
+    /// Creates a run of `command` by alice as `do_as`. alice is a member of admins, and postgres
+    /// of webapps
+    fn run<'a>(config: &'a Config, do_as: User, command: &[&str]) -> Run<'a> {
+        let backend = TestBackend::default()
+            .with_user(test_user("alice", 1000, 1000))
+            .with_user(test_user("postgres", 1001, 1001))
+            .with_group(test_group("admins", 20, &["alice"]))
+            .with_group(test_group("webapps", 30, &["postgres"]));
+
+        Run {
+            backend: Box::new(backend),
+            actions: Vec::new(),
+            flags: HashSet::new(),
+            command: Some(command.iter().map(|c| c.to_string()).collect()),
+            executable: resolve_command(command[0], DEFAULT_SAFE_PATH),
+            command_path: locate_command(command[0], DEFAULT_SAFE_PATH),
+            edit_files: None,
+            cache_user: None,
+            user: test_user("alice", 1000, 1000),
+            do_as,
+            group: None,
+            env_policy: EnvPolicy::default(),
+            cached: false,
+            config,
+        }
+    }
+
+    fn root() -> User {
+        test_user("root", 0, 0)
+    }
+
+    fn rule_action(command: &str) -> Action {
+        Action::from_rule(
+            &Rule::new("%admin".into(), "ALL".into(), "root".into(), command.into()),
+            "",
+        )
+    }
+
+    fn attempt(command: &[&str]) -> Action {
+        Action {
+            command: ActionValue::from(command[0]),
+            command_path: None,
+            args: ArgsValue::Exact(command[1..].iter().map(|a| a.to_string()).collect()),
+            host: HostValue::default(),
+            current_host: Some(Host::new("host", Vec::new())),
+            do_as: RunAs::User(ActionValue::from("root")),
+            do_as_uid: Some(Uid::from_raw(0)),
+            do_as_groups: vec!["root".into(), "wheel".into()],
+            group: None,
+        }
+    }
+
+    #[test]
+    fn command_without_args_allows_any_args() {
+        let rule = rule_action("systemctl");
+        assert!(rule.contains(&attempt(&["systemctl"])));
+        assert!(rule.contains(&attempt(&["systemctl", "disable", "firewalld"])));
+        assert!(!rule.contains(&attempt(&["journalctl"])));
+    }
+
+    #[test]
+    fn exact_args() {
+        let rule = rule_action("systemctl restart nginx");
+        assert!(rule.contains(&attempt(&["systemctl", "restart", "nginx"])));
+        assert!(!rule.contains(&attempt(&["systemctl", "restart"])));
+        assert!(!rule.contains(&attempt(&["systemctl", "restart", "nginx", "sshd"])));
+        assert!(!rule.contains(&attempt(&["systemctl", "disable", "firewalld"])));
+    }
+
+    #[test]
+    fn wildcard_args() {
+        let rule = rule_action("systemctl restart nginx*");
+        assert!(rule.contains(&attempt(&["systemctl", "restart", "nginx"])));
+        assert!(rule.contains(&attempt(&["systemctl", "restart", "nginx.service"])));
+        assert!(!rule.contains(&attempt(&["systemctl", "restart", "sshd"])));
+        assert!(!rule.contains(&attempt(&["systemctl", "stop", "nginx"])));
+
+        // The wildcard only applies to the last argument, so it can't add more
+        assert!(!rule.contains(&attempt(&["systemctl", "restart", "nginx", "sshd"])));
+        assert!(!rule.contains(&attempt(&["systemctl", "restart", "nginx", "firewalld"])));
+        assert!(!rule.contains(&attempt(&["systemctl", "restart"])));
+        assert!(!rule.contains(&attempt(&["systemctl", "restart nginx"])));
+    }
+
+    #[test]
+    fn glob_commands() {
+        let rule = rule_action("/usr/local/bin/*");
+        assert!(rule.contains(&attempt(&["/usr/local/bin/backup", "--now"])));
+        assert!(!rule.contains(&attempt(&["/usr/local/bin/admin/backup"])));
+        assert!(!rule.contains(&attempt(&["/usr/bin/backup"])));
+    }
+
+    #[test]
+    fn symlinked_commands() {
+        // /usr/local/bin/backup is a symlink to /opt/backup/bin/backup
+        let mut backup = attempt(&["/opt/backup/bin/backup"]);
+        backup.command_path = Some("/usr/local/bin/backup".into());
+
+        assert!(rule_action("/usr/local/bin/*").contains(&backup));
+        assert!(rule_action("^/usr/local/bin/.*$").contains(&backup));
+        assert!(rule_action("/opt/backup/bin/*").contains(&backup));
+        assert!(!rule_action("/usr/bin/*").contains(&backup));
+    }
+
+    #[test]
+    fn symlink_aliases() {
+        // /usr/bin/rbash is a symlink to /usr/bin/bash, but restricts what bash can do
+        let mut rbash = attempt(&["/usr/bin/bash"]);
+        rbash.command_path = Some("/usr/bin/rbash".into());
+        let mut bash = attempt(&["/usr/bin/bash", "-c", "id"]);
+        bash.command_path = Some("/usr/bin/bash".into());
+
+        assert!(rule_action("/usr/bin/rbash").contains(&rbash));
+        assert!(!rule_action("/usr/bin/rbash").contains(&bash));
+        assert!(!rule_action("/usr/bin/bash").contains(&rbash));
+    }
+
+    #[test]
+    fn regex_commands() {
+        let rule = rule_action("^/usr/s?bin/(reboot|shutdown)$");
+        assert!(rule.contains(&attempt(&["/usr/sbin/reboot"])));
+        assert!(rule.contains(&attempt(&["/usr/bin/shutdown", "now"])));
+        assert!(!rule.contains(&attempt(&["/usr/sbin/rebooter"])));
+
+        // Alternations are anchored at both ends
+        let rule = rule_action("^/usr/bin/reboot|/usr/bin/shutdown$");
+        assert!(rule.contains(&attempt(&["/usr/bin/reboot"])));
+        assert!(rule.contains(&attempt(&["/usr/bin/shutdown"])));
+        assert!(!rule.contains(&attempt(&["/home/eve/usr/bin/shutdown"])));
+        assert!(!rule.contains(&attempt(&["/usr/bin/reboot-eve"])));
+    }
+
+    #[test]
+    fn value_patterns() {
+        assert!(ActionValue::from("web-*").matches("web-01"));
+        assert!(!ActionValue::from("web-*").matches("db-01"));
+        assert!(ActionValue::from("^(web|db)-[0-9]+$").matches("db-12"));
+        assert!(!ActionValue::from("^(web|db)-[0-9]+$").matches("db-12.example.com"));
+        assert!(ActionValue::from("ALL").matches("anything"));
+        assert!(ActionValue::parse("^(unclosed$").is_err());
+    }
+
+    #[test]
+    fn run_as_group() {
+        let mut with_group = attempt(&["/usr/bin/ls"]);
+        with_group.group = Some(ActionValue::from("www-data"));
+
+        let rule = rule_action("ALL");
+        assert!(rule.contains(&attempt(&["/usr/bin/ls"])));
+        assert!(!rule.contains(&with_group));
+
+        let rule = Action::from_rule(
+            &Rule::new("%admin".into(), "ALL".into(), "root".into(), "ALL".into())
+                .with_group("www-*".into()),
+            "",
+        );
+        assert!(rule.contains(&attempt(&["/usr/bin/ls"])));
+        assert!(rule.contains(&with_group));
+    }
+
+    #[test]
+    fn run_as_group_members() {
+        let rule = |user: &str| {
+            Action::from_rule(
+                &Rule::new("%admin".into(), "ALL".into(), user.into(), "ALL".into()),
+                "",
+            )
+        };
+        let mut www_data = attempt(&["/usr/bin/ls"]);
+        www_data.do_as = RunAs::User(ActionValue::from("www-data"));
+        www_data.do_as_groups = vec!["www-data".into(), "webapps".into()];
+
+        assert!(rule("%webapps").contains(&www_data));
+        assert!(rule("%web*").contains(&www_data));
+        assert!(!rule("%webapps").contains(&attempt(&["/usr/bin/ls"])));
+        assert!(!rule("webapps").contains(&www_data));
+    }
+
+    #[test]
+    fn edit_rules() {
+        let rule = rule_action("udoedit /etc/nginx/*");
+        let mut edit = attempt(&["udoedit", "/etc/nginx/nginx.conf"]);
+        assert!(rule.contains(&edit));
+
+        edit.args = ArgsValue::Exact(vec!["/etc/shadow".into()]);
+        assert!(!rule.contains(&edit));
+        assert!(rule_action("ALL").contains(&edit));
+        assert!(!rule_action("/usr/bin/vi").contains(&edit));
+
+        // Globs don't match files in subdirectories, or other files starting with the same path
+        edit.args = ArgsValue::Exact(vec!["/etc/nginx/sites/default".into()]);
+        assert!(!rule.contains(&edit));
+        edit.args = ArgsValue::Exact(vec!["/etc/nginx.bak/nginx.conf".into()]);
+        assert!(!rule_action("udoedit /etc/nginx*").contains(&edit));
+
+        // Any of the files of a rule can be edited
+        let rule = rule_action("udoedit /etc/hosts /etc/motd");
+        edit.args = ArgsValue::Exact(vec!["/etc/motd".into()]);
+        assert!(rule.contains(&edit));
+        edit.args = ArgsValue::Exact(vec!["/etc/hostname".into()]);
+        assert!(!rule.contains(&edit));
+    }
+
+    #[test]
+    fn empty_args() {
+        let rule = rule_action(r#"passwd """#);
+        assert!(rule.contains(&attempt(&["passwd"])));
+        assert!(!rule.contains(&attempt(&["passwd", "root"])));
+    }
+
+    #[test]
+    fn last_matching_rule_decides() {
+        let rule =
+            |command: &str| Rule::new("%wheel".into(), "ALL".into(), "root".into(), command.into());
+        let rules = [
+            rule("ALL"),
+            rule("/usr/bin/passwd root").with_deny(),
+            rule("/usr/bin/passwd root --status"),
+        ]
+        .into_iter()
+        .enumerate()
+        .collect::<Vec<_>>();
+        let actions = rules
+            .iter()
+            .map(|(_, r)| Action::from_rule(r, ""))
+            .collect::<Vec<_>>();
+
+        let decision = decide(&rules, &actions, &attempt(&["/usr/bin/ls"]));
+        assert!(decision.allowed());
+        assert_eq!(decision.rule.unwrap().0, 0);
+
+        let decision = decide(&rules, &actions, &attempt(&["/usr/bin/passwd", "root"]));
+        assert!(!decision.allowed());
+        assert_eq!(decision.rule.unwrap().0, 1);
+
+        let decision = decide(
+            &rules,
+            &actions,
+            &attempt(&["/usr/bin/passwd", "root", "--status"]),
+        );
+        assert!(decision.allowed());
+
+        assert!(!decide(&rules[1..], &actions[1..], &attempt(&["/usr/bin/ls"])).allowed());
+    }
+
+    #[test]
+    fn env_policy_from_config() {
+        let rule: Rule = toml::from_str(
+            r#"
+            target = "%wheel"
+            host = "ALL"
+            user = "root"
+            command = "ALL"
+            keepenv = ["SSH_AUTH_SOCK"]
+            setenv = { FOO = "bar" }
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(rule.env.keepenv, ["SSH_AUTH_SOCK"]);
+        assert_eq!(rule.env.setenv["FOO"], "bar");
+        assert!(!rule.env.allow_preserve_env);
+    }
+
+    #[test]
+    fn nopass_rules() {
+        let rules = [
+            Rule::new("%wheel".into(), "ALL".into(), "root".into(), "ALL".into()),
+            Rule::new(
+                "%wheel".into(),
+                "ALL".into(),
+                "root".into(),
+                "/usr/bin/systemctl suspend".into(),
+            )
+            .with_nopass(),
+        ]
+        .into_iter()
+        .enumerate()
+        .collect::<Vec<_>>();
+        let actions = rules
+            .iter()
+            .map(|(_, r)| Action::from_rule(r, ""))
+            .collect::<Vec<_>>();
+
+        let suspend = attempt(&["/usr/bin/systemctl", "suspend"]);
+        assert!(decide(&rules, &actions, &suspend).nopass());
+        let reboot = attempt(&["/usr/bin/systemctl", "reboot"]);
+        assert!(!decide(&rules, &actions, &reboot).nopass());
+    }
+
+    #[test]
+    fn aliases() {
+        let aliases = Aliases {
+            users: BTreeMap::from([("OPS".into(), vec!["udo-alice".into(), "root".into()])]),
+            hosts: BTreeMap::from([("WEB".into(), vec!["web1".into(), "web2".into()])]),
+            commands: BTreeMap::from([(
+                "NETWORK".into(),
+                vec!["/usr/bin/ip".into(), "/usr/bin/nmcli".into()],
+            )]),
+        };
+        let rule = Rule::new("OPS".into(), "WEB".into(), "root".into(), "NETWORK".into());
+        let expanded = rule.expand(&aliases);
+        assert_eq!(expanded.len(), 8);
+
+        assert!(
+            rule.applies_to(&root(), &aliases, &TestBackend::default())
+                .unwrap()
+        );
+
+        let rules = expanded.iter().map(|r| (0, r.clone())).collect::<Vec<_>>();
+        let actions = expanded
+            .iter()
+            .map(|r| Action::from_rule(r, ""))
+            .collect::<Vec<_>>();
+        let mut nmcli = attempt(&["/usr/bin/nmcli"]);
+        nmcli.current_host = Some(Host::new("web2", Vec::new()));
+        assert!(decide(&rules, &actions, &nmcli).allowed());
+
+        let mut db = attempt(&["/usr/bin/ip"]);
+        db.current_host = Some(Host::new("db1", Vec::new()));
+        assert!(!decide(&rules, &actions, &db).allowed());
+        assert!(!decide(&rules, &actions, &attempt(&["/usr/bin/ss"])).allowed());
+    }
+
+    #[test]
+    fn run_as_named_user_only() {
+        let config = config(vec![Rule::new(
+            "alice".into(),
+            "ALL".into(),
+            "root".into(),
+            "ALL".into(),
+        )]);
+
+        let as_root = run(&config, root(), &["/bin/sh"]);
+        assert!(check_action_auth(&as_root, &config).allowed());
+        let as_postgres = run(&config, test_user("postgres", 1001, 1001), &["/bin/sh"]);
+        assert!(!check_action_auth(&as_postgres, &config).allowed());
+        // A user with root's uid but another name isn't root as far as the rule is concerned
+        let as_toor = run(&config, test_user("toor", 0, 0), &["/bin/sh"]);
+        assert!(!check_action_auth(&as_toor, &config).allowed());
+    }
+
+    #[test]
+    fn run_as_uid() {
+        let config = config(vec![Rule::new(
+            "alice".into(),
+            "ALL".into(),
+            "#1001".into(),
+            "ALL".into(),
+        )]);
+
+        let as_postgres = run(&config, test_user("postgres", 1001, 1001), &["/bin/sh"]);
+        assert!(check_action_auth(&as_postgres, &config).allowed());
+        let as_mysql = run(&config, test_user("mysql", 1002, 1002), &["/bin/sh"]);
+        assert!(!check_action_auth(&as_mysql, &config).allowed());
+        assert!(!check_action_auth(&run(&config, root(), &["/bin/sh"]), &config).allowed());
+    }
+
+    #[test]
+    fn run_as_any_user() {
+        let config = config(vec![Rule::new(
+            "alice".into(),
+            "ALL".into(),
+            "ALL".into(),
+            "ALL".into(),
+        )]);
+
+        assert!(check_action_auth(&run(&config, root(), &["/bin/sh"]), &config).allowed());
+        let as_postgres = run(&config, test_user("postgres", 1001, 1001), &["/bin/sh"]);
+        assert!(check_action_auth(&as_postgres, &config).allowed());
+    }
+
+    #[test]
+    fn rules_for_other_users() {
+        let config = config(vec![
+            Rule::new("bob".into(), "ALL".into(), "root".into(), "ALL".into()),
+            Rule::new(
+                "#1000".into(),
+                "ALL".into(),
+                "root".into(),
+                "/bin/sh".into(),
+            ),
+        ]);
+
+        let decision = check_action_auth(&run(&config, root(), &["/bin/sh"]), &config);
+        assert_eq!(decision.rule.map(|(i, _)| i), Some(1));
+        assert!(!check_action_auth(&run(&config, root(), &["/bin/cat"]), &config).allowed());
+    }
+
+    #[test]
+    fn group_rules() {
+        let config = config(vec![Rule::new(
+            "%admins".into(),
+            "ALL".into(),
+            "%webapps".into(),
+            "ALL".into(),
+        )]);
+
+        let as_postgres = run(&config, test_user("postgres", 1001, 1001), &["/bin/sh"]);
+        assert!(check_action_auth(&as_postgres, &config).allowed());
+        assert!(!check_action_auth(&run(&config, root(), &["/bin/sh"]), &config).allowed());
+
+        // Membership is looked up in the backend's group database, including the primary group
+        let backend = TestBackend::default();
+        let rule = &config.rules[0];
+        assert!(
+            !rule
+                .applies_to(
+                    &test_user("alice", 1000, 1000),
+                    &Aliases::default(),
+                    &backend
+                )
+                .unwrap()
+        );
+        let backend = backend.with_group(test_group("admins", 20, &[]));
+        assert!(
+            rule.applies_to(&test_user("bob", 1001, 20), &Aliases::default(), &backend)
+                .unwrap()
+        );
+    }
+
+    #[test]
+    fn failed_lookups_deny() {
+        let rule = |target: &str, user: &str| {
+            Rule::new(target.into(), "ALL".into(), user.into(), "ALL".into())
+        };
+        // alice is denied as a member of admins, and can't run commands as members of webapps
+        let targets_group = config(vec![
+            rule("alice", "ALL"),
+            rule("%admins", "ALL").with_deny(),
+        ]);
+        let runs_as_group = config(vec![
+            rule("alice", "ALL"),
+            rule("alice", "%webapps").with_deny(),
+        ]);
+        let failing = || Box::new(TestBackend::default().with_failing_groups());
+
+        let mut as_root = run(&targets_group, root(), &["/bin/sh"]);
+        assert!(!check_action_auth(&as_root, &targets_group).allowed());
+        let mut as_postgres = run(
+            &runs_as_group,
+            test_user("postgres", 1001, 1001),
+            &["/bin/sh"],
+        );
+        assert!(!check_action_auth(&as_postgres, &runs_as_group).allowed());
+
+        // If groups can't be looked up, the deny rules can't be checked, so nothing is allowed
+        // rather than only the rules targeting alice by name
+        as_root.backend = failing();
+        let decision = check_action_auth(&as_root, &targets_group);
+        assert!(!decision.allowed());
+        assert!(decision.error.is_some());
+        as_postgres.backend = failing();
+        let decision = check_action_auth(&as_postgres, &runs_as_group);
+        assert!(!decision.allowed());
+        assert!(decision.error.is_some());
+    }
+
+    #[test]
+    fn validate_needs_a_rule() {
+        let rule = |target: &str, command: &str| {
+            Rule::new(target.into(), "ALL".into(), "root".into(), command.into())
+        };
+        let validate = |rules| {
+            let config = config(rules);
+            check_validate_auth(&run(&config, root(), &["/bin/sh"]), &config)
+        };
+
+        // Users without rules, or only with deny rules, can't log in just to cache it
+        assert!(!validate(vec![rule("bob", "ALL")]).allowed());
+        assert!(!validate(vec![rule("alice", "ALL").with_deny()]).allowed());
+        let elsewhere = Rule::new(
+            "alice".into(),
+            "udo-test-elsewhere".into(),
+            "root".into(),
+            "ALL".into(),
+        );
+        assert!(!validate(vec![elsewhere]).allowed());
+
+        // A rule requiring a password is preferred, so the login is cached
+        let decision = validate(vec![
+            rule("alice", "/bin/sh"),
+            rule("%admins", "/bin/ls").with_nopass(),
+        ]);
+        assert!(decision.allowed() && !decision.nopass());
+        assert!(validate(vec![rule("alice", "/bin/ls").with_nopass()]).nopass());
+    }
+
+    #[test]
+    fn uid_syntax() {
+        assert_eq!(super::parse_uid("#1001"), Some(Uid::from_raw(1001)));
+        assert_eq!(super::parse_uid("1001"), None);
+        assert_eq!(super::parse_uid("#postgres"), None);
+    }
 }
@@ -0,0 +1,214 @@
+use std::{
+    cell::OnceCell,
+    ffi::{CStr, CString},
+    net::IpAddr,
+    ptr,
+};
+
+use ipnet::IpNet;
+use nix::{ifaddrs::getifaddrs, libc, unistd::gethostname};
+use regex::Regex;
+
+use crate::authenticate::ActionValue;
+
+/// HostValue represents the hosts an [Action](super::Action) can be run on.
+///
+/// In a [Rule](super::Rule), IP addresses and CIDR ranges like `10.0.0.0/8` match the addresses
+/// of the host, and everything else is an [ActionValue] matching its names. Hostnames are case
+/// insensitive, so names are matched ignoring case.
+#[derive(Debug, Clone)]
+pub enum HostValue {
+    Name(ActionValue),
+    Network(IpNet),
+}
+
+impl Default for HostValue {
+    fn default() -> Self {
+        Self::Name(ActionValue::Any)
+    }
+}
+
+impl From<&String> for HostValue {
+    fn from(value: &String) -> Self {
+        if let Ok(net) = value.parse::<IpNet>() {
+            return Self::Network(net);
+        }
+
+        match value.parse::<IpAddr>() {
+            Ok(addr) => Self::Network(addr.into()),
+            Err(_) => Self::Name(name_value(value)),
+        }
+    }
+}
+
+/// Parses a name in a rule so it matches the host's names, which are lowercase. Regexes are made
+/// case insensitive instead, as lowercasing them would change escapes like `\D`
+fn name_value(value: &str) -> ActionValue {
+    match ActionValue::from(value) {
+        ActionValue::Regex(r) => Regex::new(&format!("(?i){}", r.as_str()))
+            .map(ActionValue::Regex)
+            .unwrap_or(ActionValue::Regex(r)),
+        ActionValue::Any => ActionValue::Any,
+        _ => ActionValue::from(value.to_lowercase()),
+    }
+}
+
+/// Host is the machine udo is running on, which the host of a [Rule](super::Rule) is matched
+/// against
+#[derive(Debug, Clone)]
+pub struct Host {
+    /// The hostname, and its short name if it's qualified
+    pub names: Vec<String>,
+    /// The addresses of the host's network interfaces
+    pub addresses: Vec<IpAddr>,
+    /// The fully qualified name of the host. It's only looked up if `security.fqdn` is set, and
+    /// then only when a rule needs it, as the lookup can be slow
+    fqdn: OnceCell<Option<String>>,
+}
+
+impl Host {
+    /// Identifies the current host, returning [None] if its hostname couldn't be retrieved. Its
+    /// fully qualified name is only looked up if `fqdn` is set
+    pub fn current(fqdn: bool) -> Option<Self> {
+        let hostname = gethostname().ok()?.to_string_lossy().to_string();
+        let addresses = getifaddrs()
+            .map(|addrs| {
+                addrs
+                    .filter_map(|a| a.address)
+                    .filter_map(|a| {
+                        a.as_sockaddr_in()
+                            .map(|a| IpAddr::V4(a.ip()))
+                            .or(a.as_sockaddr_in6().map(|a| IpAddr::V6(a.ip())))
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        let mut host = Self::new(&hostname, addresses);
+        if fqdn {
+            host.fqdn = OnceCell::new();
+        }
+        Some(host)
+    }
+
+    /// Creates a host which is only known by `hostname` and the short name, without looking up
+    /// its fully qualified name
+    pub fn new(hostname: &str, addresses: Vec<IpAddr>) -> Self {
+        let hostname = hostname.to_lowercase();
+        let mut names = vec![hostname.clone()];
+        if let Some((short, _)) = hostname.split_once('.') {
+            names.push(short.to_string());
+        }
+
+        Self {
+            names,
+            addresses,
+            fqdn: OnceCell::from(None),
+        }
+    }
+
+    /// Checks if the host is matched by `value`. A name matches the hostname, its short name, or
+    /// its fully qualified name if it's looked up
+    pub fn matches(&self, value: &HostValue) -> bool {
+        match value {
+            HostValue::Name(ActionValue::Any) => true,
+            HostValue::Name(v) => {
+                self.names.iter().any(|n| v.matches(n)) || self.fqdn().is_some_and(|n| v.matches(n))
+            }
+            HostValue::Network(net) => self.addresses.iter().any(|a| net.contains(a)),
+        }
+    }
+
+    fn fqdn(&self) -> Option<&String> {
+        self.fqdn
+            .get_or_init(|| match self.names.first() {
+                // A qualified hostname is already the fully qualified name
+                Some(name) if !name.contains('.') => lookup_fqdn(name),
+                _ => None,
+            })
+            .as_ref()
+    }
+}
+
+/// Looks up the canonical name of a host, like `hostname --fqdn`
+fn lookup_fqdn(hostname: &str) -> Option<String> {
+    let node = CString::new(hostname).ok()?;
+    let hints = libc::addrinfo {
+        ai_flags: libc::AI_CANONNAME,
+        ..unsafe { std::mem::zeroed() }
+    };
+    let mut res: *mut libc::addrinfo = ptr::null_mut();
+
+    if unsafe { libc::getaddrinfo(node.as_ptr(), ptr::null(), &hints, &mut res) } != 0 {
+        return None;
+    }
+
+    let canonname = unsafe { (*res).ai_canonname };
+    let fqdn = if canonname.is_null() {
+        None
+    } else {
+        let name = unsafe { CStr::from_ptr(canonname) };
+        Some(name.to_string_lossy().to_lowercase())
+    };
+    unsafe { libc::freeaddrinfo(res) };
+
+    fqdn.filter(|n| n.contains('.'))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::IpAddr;
+
+    use crate::authenticate::host::{Host, HostValue};
+
+    fn host() -> Host {
+        Host::new(
+            "web1.example.com",
+            vec!["10.1.2.3".parse::<IpAddr>().unwrap()],
+        )
+    }
+
+    fn value(value: &str) -> HostValue {
+        HostValue::from(&value.to_string())
+    }
+
+    #[test]
+    fn names() {
+        let host = host();
+        assert!(host.matches(&value("ALL")));
+        assert!(host.matches(&value("web1")));
+        assert!(host.matches(&value("web1.example.com")));
+        assert!(host.matches(&value("web*")));
+        assert!(host.matches(&value("*.example.com")));
+        assert!(!host.matches(&value("web2")));
+        assert!(!host.matches(&value("db*")));
+    }
+
+    #[test]
+    fn names_ignore_case() {
+        let host = Host::new("WebServer.Example.com", Vec::new());
+        assert!(host.matches(&value("webserver")));
+        assert!(host.matches(&value("WebServer")));
+        assert!(host.matches(&value("WEBSERVER.example.COM")));
+        assert!(host.matches(&value("Web*")));
+        assert!(host.matches(&value("^WEB[a-z]+\\.\\D+\\.com$")));
+        assert!(!host.matches(&value("WebServer2")));
+    }
+
+    #[test]
+    fn fqdn_is_opt_in() {
+        // Without the option the fully qualified name is never looked up
+        let host = Host::current(false).unwrap();
+        assert!(host.fqdn().is_none());
+    }
+
+    #[test]
+    fn networks() {
+        let host = host();
+        assert!(host.matches(&value("10.1.2.3")));
+        assert!(host.matches(&value("10.0.0.0/8")));
+        assert!(!host.matches(&value("10.1.2.4")));
+        assert!(!host.matches(&value("192.168.0.0/16")));
+        assert!(!host.matches(&value("fd00::/8")));
+    }
+}
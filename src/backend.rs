@@ -9,19 +9,26 @@ pub mod system;
 #[cfg(test)]
 pub mod testing;
 
-use std::fmt::Display;
+use std::{
+    fmt::Display,
+    path::{Path, PathBuf},
+};
 
-use nix::unistd::{Gid, Uid};
+use nix::unistd::{Gid, Group, Uid, User};
 
 #[derive(Debug, Clone)]
 pub enum ErrorKind {
     UidSet,
     EuidSet,
     GidSet,
+    GroupsSet,
     InvalidString,
     DoesNotExist,
+    AlreadyExists,
     Exec,
     Env,
+    Io,
+    Lookup,
 }
 
 impl Display for ErrorKind {
@@ -30,10 +37,14 @@ impl Display for ErrorKind {
             Self::UidSet => "UID_SET",
             Self::EuidSet => "EUID_SET",
             Self::GidSet => "GID_SET",
+            Self::GroupsSet => "GROUPS_SET",
             Self::InvalidString => "INVALID_STRING",
             Self::Exec => "EXEC",
             Self::Env => "ENV",
             Self::DoesNotExist => "DOES_NOT_EXIST",
+            Self::AlreadyExists => "ALREADY_EXISTS",
+            Self::Io => "IO",
+            Self::Lookup => "LOOKUP",
         })
     }
 }
@@ -63,6 +74,15 @@ impl Error {
 
 pub type Result<T> = std::result::Result<T, Error>;
 
+/// The ownership and permissions of a file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FileInfo {
+    pub owner: Uid,
+    /// The permission bits of the file, e.g. `0o644`
+    pub mode: u32,
+    pub is_dir: bool,
+}
+
 pub trait Backend {
     fn getuid(&self) -> Uid;
     /// Sets the process uid, euid, and suid
@@ -75,6 +95,9 @@ pub trait Backend {
     fn getgid(&self) -> Gid;
     fn setgid(&self, uid: Gid) -> Result<()>;
 
+    /// Sets the supplementary groups of the process to those of `user`, plus `group`
+    fn initgroups(&self, user: &str, group: Gid) -> Result<()>;
+
     fn execvp(&self, process: &str, args: &[&str]) -> Result<()>;
 
     /// Get an environment variable
@@ -86,13 +109,47 @@ pub trait Backend {
     /// Get all environment variables as key value pairs
     fn vars(&self) -> Vec<(String, String)>;
 
+    /// Look up a user by name in the user database
+    fn user_by_name(&self, name: &str) -> Result<Option<User>>;
+    /// Look up a user by uid in the user database
+    fn user_by_uid(&self, uid: Uid) -> Result<Option<User>>;
+    /// Look up a group by name in the group database
+    fn group_by_name(&self, name: &str) -> Result<Option<Group>>;
+    /// Look up a group by gid in the group database
+    fn group_by_gid(&self, gid: Gid) -> Result<Option<Group>>;
+    /// Get the supplementary groups of a user, which may include their primary group
+    fn supplementary_groups(&self, user: &User) -> Result<Vec<Group>>;
+
+    /// Get the ownership and permissions of a file, following symlinks
+    fn file_info(&self, path: &Path) -> Result<FileInfo>;
+    /// Get the paths of the entries of a directory, in no particular order
+    fn read_dir(&self, path: &Path) -> Result<Vec<PathBuf>>;
+    /// Read the content of a file
+    fn read_file(&self, path: &Path) -> Result<String>;
+    /// Write a file, creating it if it doesn't exist. Its permissions are set to `mode` either way
+    fn write_file(&self, path: &Path, content: &str, mode: u32) -> Result<()>;
+    /// Create a file with the permissions `mode`, failing with [ErrorKind::AlreadyExists] if it
+    /// exists. The file only appears once its content is fully written
+    fn create_file(&self, path: &Path, content: &str, mode: u32) -> Result<()>;
+    /// Create a directory and its parents if it doesn't exist. Its permissions are set to `mode`
+    /// either way
+    fn create_dir(&self, path: &Path, mode: u32) -> Result<()>;
+    /// Remove a file
+    fn remove_file(&self, path: &Path) -> Result<()>;
+    /// Remove a directory and everything in it
+    fn remove_dir_all(&self, path: &Path) -> Result<()>;
+
+    /// Fill `buf` with cryptographically secure random bytes
+    fn random_bytes(&self, buf: &mut [u8]) -> Result<()>;
+
     /// Elevate to root for privileged operations
     fn elevate(&self) -> Result<()>;
 
     /// Restore to the original user
     fn restore(&self) -> Result<()>;
 
-    /// Make the final switch (setuid) to the target user
+    /// Make the final switch to the target user, setting the supplementary groups and gid before
+    /// the uid
     fn switch_final(&self) -> Result<()>;
 
     /// Return if the process is currently "effectively" root, i.e. euid == 0 || uid == 0
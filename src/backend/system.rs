@@ -1,8 +1,19 @@
-use std::{env, ffi::CString, fs};
+use std::{
+    env,
+    ffi::CString,
+    fs::{self, File, OpenOptions, Permissions},
+    io::{self, Read, Write},
+    os::unix::fs::{MetadataExt, OpenOptionsExt, PermissionsExt},
+    path::{Path, PathBuf},
+};
 
-use nix::unistd::{Gid, Uid, execvp, getuid, seteuid, setgid, setuid};
+use anyhow::Result as AnyResult;
+use nix::libc;
+#[cfg(not(target_os = "macos"))]
+use nix::unistd::initgroups;
+use nix::unistd::{Gid, Group, Uid, User, execvp, getuid, seteuid, setgid, setuid};
 
-use crate::backend::{Backend, Error, ErrorKind, Result};
+use crate::backend::{Backend, Error, ErrorKind, FileInfo, Result};
 
 /// This is a [Backend] used for running udo. It interacts directly with the system
 /// it is running on, and all actions performed on it reflect directly on the system
@@ -10,13 +21,30 @@ use crate::backend::{Backend, Error, ErrorKind, Result};
 pub struct SystemBackend {
     original: Uid,
     target: Uid,
+    target_gid: Gid,
+    target_name: String,
 }
 
 impl SystemBackend {
-    pub fn new(target: Uid) -> Self {
+    /// Creates a backend which switches to the `target` user with the primary group `target_gid`
+    pub fn new(target: &User, target_gid: Gid) -> Self {
         Self {
             original: getuid(),
-            target,
+            target: target.uid,
+            target_gid,
+            target_name: target.name.clone(),
+        }
+    }
+}
+
+impl Default for SystemBackend {
+    /// Creates a backend which switches to root, like udo does by default
+    fn default() -> Self {
+        Self {
+            original: getuid(),
+            target: Uid::from_raw(0),
+            target_gid: Gid::from_raw(0),
+            target_name: "root".to_string(),
         }
     }
 }
@@ -46,6 +74,37 @@ impl Backend for SystemBackend {
         setgid(gid).map_err(|e| Error::new(ErrorKind::GidSet, "Failed to set gid"))
     }
 
+    #[cfg(not(target_os = "macos"))]
+    fn initgroups(&self, user: &str, group: Gid) -> Result<()> {
+        let user = CString::new(user).map_err(|_| {
+            Error::new(
+                ErrorKind::InvalidString,
+                "Failed to convert user to CString",
+            )
+        })?;
+        initgroups(&user, group)
+            .map_err(|_| Error::new(ErrorKind::GroupsSet, "Failed to set supplementary groups"))
+    }
+
+    // nix doesn't expose initgroups on Apple platforms, so we call it directly
+    #[cfg(target_os = "macos")]
+    fn initgroups(&self, user: &str, group: Gid) -> Result<()> {
+        let user = CString::new(user).map_err(|_| {
+            Error::new(
+                ErrorKind::InvalidString,
+                "Failed to convert user to CString",
+            )
+        })?;
+        let res = unsafe { libc::initgroups(user.as_ptr(), group.as_raw() as libc::c_int) };
+        if res != 0 {
+            return Err(Error::new(
+                ErrorKind::GroupsSet,
+                "Failed to set supplementary groups",
+            ));
+        }
+        Ok(())
+    }
+
     fn execvp(&self, process: &str, args: &[&str]) -> Result<()> {
         let process = CString::new(process).map_err(|_| {
             Error::new(
@@ -96,6 +155,99 @@ impl Backend for SystemBackend {
         env::vars().collect()
     }
 
+    fn user_by_name(&self, name: &str) -> Result<Option<User>> {
+        User::from_name(name).map_err(|e| lookup_error(format!("user {name}"), e))
+    }
+
+    fn user_by_uid(&self, uid: Uid) -> Result<Option<User>> {
+        User::from_uid(uid).map_err(|e| lookup_error(format!("user {uid}"), e))
+    }
+
+    fn group_by_name(&self, name: &str) -> Result<Option<Group>> {
+        Group::from_name(name).map_err(|e| lookup_error(format!("group {name}"), e))
+    }
+
+    fn group_by_gid(&self, gid: Gid) -> Result<Option<Group>> {
+        Group::from_gid(gid).map_err(|e| lookup_error(format!("group {gid}"), e))
+    }
+
+    fn supplementary_groups(&self, user: &User) -> Result<Vec<Group>> {
+        get_supplemental_groups(user)
+            .map_err(|e| lookup_error(format!("groups of {}", user.name), e))
+    }
+
+    fn file_info(&self, path: &Path) -> Result<FileInfo> {
+        let md = fs::metadata(path).map_err(|e| io_error("read metadata of", path, e))?;
+
+        Ok(FileInfo {
+            owner: Uid::from_raw(md.uid()),
+            mode: md.mode() & 0o7777,
+            is_dir: md.is_dir(),
+        })
+    }
+
+    fn read_dir(&self, path: &Path) -> Result<Vec<PathBuf>> {
+        fs::read_dir(path)
+            .and_then(|entries| entries.map(|e| e.map(|e| e.path())).collect())
+            .map_err(|e| io_error("read", path, e))
+    }
+
+    fn read_file(&self, path: &Path) -> Result<String> {
+        fs::read_to_string(path).map_err(|e| io_error("read", path, e))
+    }
+
+    fn write_file(&self, path: &Path, content: &str, mode: u32) -> Result<()> {
+        OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .mode(mode)
+            .open(path)
+            .and_then(|mut f| f.write_all(content.as_bytes()))
+            // The mode is only used when the file is created
+            .and_then(|_| fs::set_permissions(path, Permissions::from_mode(mode)))
+            .map_err(|e| io_error("write", path, e))
+    }
+
+    fn create_file(&self, path: &Path, content: &str, mode: u32) -> Result<()> {
+        // The content is written to a temporary file first, which is then linked into place.
+        // Unlike renaming, linking fails if the file already exists
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        let temp = path.with_file_name(format!(".{name}.{}", std::process::id()));
+        let res = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(mode)
+            .open(&temp)
+            .and_then(|mut f| f.write_all(content.as_bytes()))
+            .map_err(|e| io_error("write", &temp, e))
+            .and_then(|_| fs::hard_link(&temp, path).map_err(|e| io_error("create", path, e)));
+        let _ = fs::remove_file(&temp);
+
+        res
+    }
+
+    fn create_dir(&self, path: &Path, mode: u32) -> Result<()> {
+        fs::create_dir_all(path)
+            .and_then(|_| fs::set_permissions(path, Permissions::from_mode(mode)))
+            .map_err(|e| io_error("create", path, e))
+    }
+
+    fn remove_file(&self, path: &Path) -> Result<()> {
+        fs::remove_file(path).map_err(|e| io_error("remove", path, e))
+    }
+
+    fn remove_dir_all(&self, path: &Path) -> Result<()> {
+        fs::remove_dir_all(path).map_err(|e| io_error("remove", path, e))
+    }
+
+    fn random_bytes(&self, buf: &mut [u8]) -> Result<()> {
+        let path = Path::new("/dev/urandom");
+        File::open(path)
+            .and_then(|mut f| f.read_exact(buf))
+            .map_err(|e| io_error("read", path, e))
+    }
+
     fn is_root(&self) -> bool {
         self.getuid().is_root() || self.geteuid().is_root()
     }
@@ -110,6 +262,84 @@ impl Backend for SystemBackend {
 
     fn switch_final(&self) -> Result<()> {
         self.elevate()?;
+        // Groups have to be set while we're still root, so before the uid
+        self.initgroups(&self.target_name, self.target_gid)?;
+        self.setgid(self.target_gid)?;
         self.setuid(self.target)
     }
 }
+
+/// Creates an error for a failed file operation, like `io_error("read", path, e)`
+fn io_error(action: &str, path: &Path, e: io::Error) -> Error {
+    match e.kind() {
+        io::ErrorKind::NotFound => Error::new(
+            ErrorKind::DoesNotExist,
+            format!("{} does not exist", path.display()),
+        ),
+        io::ErrorKind::AlreadyExists => Error::new(
+            ErrorKind::AlreadyExists,
+            format!("{} already exists", path.display()),
+        ),
+        _ => Error::new(
+            ErrorKind::Io,
+            format!("Failed to {action} {} ({e})", path.display()),
+        ),
+    }
+}
+
+fn lookup_error<E: std::fmt::Display>(what: String, e: E) -> Error {
+    Error::new(ErrorKind::Lookup, format!("Failed to look up {what} ({e})"))
+}
+
+/// The type of group ids taken by getgrouplist, which is an int on macOS
+#[cfg(target_os = "macos")]
+type GroupListId = libc::c_int;
+#[cfg(not(target_os = "macos"))]
+type GroupListId = libc::gid_t;
+
+/// Linux's NGROUPS_MAX. No platform allows a user to be in more groups than this
+const MAX_GROUPS: usize = 65536;
+
+/// Gets the supplementary groups of `user` from the group database with getgrouplist, rather than
+/// the groups of the current process
+fn get_supplemental_groups(user: &User) -> AnyResult<Vec<Group>> {
+    let name = CString::new(user.name.as_str())?;
+    let mut gids: Vec<GroupListId> = vec![0; 32];
+
+    loop {
+        let mut ngroups = gids.len() as libc::c_int;
+        let res = unsafe {
+            libc::getgrouplist(
+                name.as_ptr(),
+                user.gid.as_raw() as GroupListId,
+                gids.as_mut_ptr(),
+                &mut ngroups,
+            )
+        };
+        if res >= 0 {
+            gids.truncate(ngroups as usize);
+            break;
+        }
+        if gids.len() >= MAX_GROUPS {
+            anyhow::bail!("{} is in too many groups", user.name);
+        }
+
+        // Linux reports how many groups there are, but macOS doesn't, so the list is grown until
+        // they all fit
+        let len = (ngroups as usize).max(gids.len() * 2).min(MAX_GROUPS);
+        gids.resize(len, 0);
+    }
+
+    let mut groups = Vec::new();
+    for gid in gids.into_iter().map(|g| Gid::from_raw(g as libc::gid_t)) {
+        // getgrouplist includes the primary group
+        if gid == user.gid || groups.iter().any(|g: &Group| g.gid == gid) {
+            continue;
+        }
+        if let Some(group) = Group::from_gid(gid)? {
+            groups.push(group);
+        }
+    }
+
+    Ok(groups)
+}
@@ -1,8 +1,17 @@
-use std::{cell::RefCell, collections::HashMap, fs};
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    ffi::CString,
+    fs,
+    path::{Path, PathBuf},
+};
 
-use nix::unistd::{Gid, Uid};
+use nix::{
+    libc,
+    unistd::{Gid, Group, Uid, User},
+};
 
-use crate::backend::{Backend, Error, ErrorKind, Result};
+use crate::backend::{Backend, Error, ErrorKind, FileInfo, Result};
 
 /// This is a [Backend] used for testing udo. It in no way fully simulates a Unix system,
 /// but it aims to simulate *enough* to verify that udo has the expected behaviour
@@ -20,13 +29,132 @@ pub struct TestBackend {
     euid: RefCell<Uid>,
     /// Stores the saved-set uid, necessary for switching the euid
     suid: RefCell<Uid>,
+    /// Stores the supplementary groups
+    groups: RefCell<Vec<Gid>>,
     /// Stores the original user UID, for use in elevate and restore functions
     original: Uid,
     target: Uid,
+    target_gid: Gid,
+    target_name: String,
     env: HashMap<String, String>,
-    /// Stores an incredibly simplified representation of files (path -> content)
-    /// We don't worry about permissions here, it's simply too much of a PITA.
-    files: HashMap<String, String>,
+    /// Stores the user database
+    users: Vec<User>,
+    /// Stores the group database
+    group_db: Vec<Group>,
+    /// Makes every group lookup fail, like an unreachable directory server would
+    failing_groups: bool,
+    /// Stores an incredibly simplified representation of files (path -> file)
+    /// We only store the owner and mode, as simulating permission checks is simply too much of a
+    /// PITA.
+    files: RefCell<HashMap<PathBuf, TestFile>>,
+    /// Stores the next byte returned by random_bytes, which simply count up
+    random: RefCell<u8>,
+}
+
+/// A file in the [TestBackend]. Directories are files without content
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub struct TestFile {
+    pub content: String,
+    pub info: FileInfo,
+}
+
+impl TestBackend {
+    /// Sets the user [Backend::switch_final] switches to, with the primary group `target_gid`
+    pub fn with_target(mut self, target: &User, target_gid: Gid) -> Self {
+        self.target = target.uid;
+        self.target_gid = target_gid;
+        self.target_name = target.name.clone();
+        self
+    }
+
+    /// Adds a user to the user database
+    pub fn with_user(mut self, user: User) -> Self {
+        self.users.push(user);
+        self
+    }
+
+    /// Adds a group to the group database
+    pub fn with_group(mut self, group: Group) -> Self {
+        self.group_db.push(group);
+        self
+    }
+
+    /// Makes every group lookup fail
+    pub fn with_failing_groups(mut self) -> Self {
+        self.failing_groups = true;
+        self
+    }
+
+    /// Adds a file to the backend, replacing any file at the same path
+    pub fn with_file<P: Into<PathBuf>>(
+        mut self,
+        path: P,
+        content: &str,
+        owner: Uid,
+        mode: u32,
+    ) -> Self {
+        self.files.get_mut().insert(
+            path.into(),
+            TestFile {
+                content: content.to_string(),
+                info: FileInfo {
+                    owner,
+                    mode,
+                    is_dir: false,
+                },
+            },
+        );
+        self
+    }
+}
+
+/// Creates a group with the given supplementary members
+pub fn test_group(name: &str, gid: u32, members: &[&str]) -> Group {
+    Group {
+        name: name.to_string(),
+        passwd: CString::default(),
+        gid: Gid::from_raw(gid),
+        mem: members.iter().map(|m| m.to_string()).collect(),
+    }
+}
+
+/// Creates a user which doesn't have to exist on the system, with the home directory `/home/name`
+pub fn test_user(name: &str, uid: u32, gid: u32) -> User {
+    let name_c = CString::new(name).unwrap();
+    let dir = CString::new(format!("/home/{name}")).unwrap();
+    let shell = CString::new("/bin/sh").unwrap();
+    let empty = CString::default();
+
+    // nix can only create users from passwd entries on every platform
+    let mut pw: libc::passwd = unsafe { std::mem::zeroed() };
+    pw.pw_name = name_c.as_ptr().cast_mut();
+    pw.pw_passwd = empty.as_ptr().cast_mut();
+    pw.pw_uid = uid;
+    pw.pw_gid = gid;
+    pw.pw_dir = dir.as_ptr().cast_mut();
+    pw.pw_shell = shell.as_ptr().cast_mut();
+    #[cfg(target_os = "macos")]
+    {
+        pw.pw_class = empty.as_ptr().cast_mut();
+    }
+
+    User::from(&pw)
+}
+
+impl TestBackend {
+    fn check_groups(&self) -> Result<()> {
+        match self.failing_groups {
+            true => Err(Error::new(ErrorKind::Lookup, "Failed to look up groups")),
+            false => Ok(()),
+        }
+    }
+}
+
+fn does_not_exist(path: &Path) -> Error {
+    Error::new(
+        ErrorKind::DoesNotExist,
+        format!("{} does not exist", path.display()),
+    )
 }
 
 impl Default for TestBackend {
@@ -48,12 +176,25 @@ impl Default for TestBackend {
             egid: RefCell::new(group),
             // Therefore, so is sgid
             sgid: RefCell::new(group),
+            // The user is only in their own group
+            groups: RefCell::new(vec![group]),
             // The original user is always the user running the program
             original: user,
             // We default the target user to root for testing purposes
             target: root,
+            target_gid: Gid::from_raw(0),
+            target_name: "root".to_string(),
             env: HashMap::new(),
-            files: HashMap::new(),
+            // The user database only has root and the user running the program, who is in wheel
+            users: vec![test_user("root", 0, 0), test_user("user", 512, 512)],
+            group_db: vec![
+                test_group("root", 0, &[]),
+                test_group("wheel", 10, &["user"]),
+                test_group("user", 512, &[]),
+            ],
+            failing_groups: false,
+            files: RefCell::new(HashMap::new()),
+            random: RefCell::new(0),
         }
     }
 }
@@ -118,6 +259,25 @@ impl Backend for TestBackend {
         Ok(())
     }
 
+    fn initgroups(&self, user: &str, group: Gid) -> Result<()> {
+        if !self.is_root() {
+            return Err(Error::new(
+                ErrorKind::GroupsSet,
+                "Process is not root, cannot set groups",
+            ));
+        }
+
+        let mut groups = vec![group];
+        groups.extend(
+            self.group_db
+                .iter()
+                .filter(|g| g.mem.iter().any(|m| m == user))
+                .map(|g| g.gid),
+        );
+        *self.groups.borrow_mut() = groups;
+        Ok(())
+    }
+
     // In our test backend, execvp doesn't actually have to do anything. Always returns Ok(())
     // without executing any code
     fn execvp(&self, process: &str, args: &[&str]) -> Result<()> {
@@ -154,6 +314,136 @@ impl Backend for TestBackend {
         self.env.remove(name);
     }
 
+    fn user_by_name(&self, name: &str) -> Result<Option<User>> {
+        Ok(self.users.iter().find(|u| u.name == name).cloned())
+    }
+
+    fn user_by_uid(&self, uid: Uid) -> Result<Option<User>> {
+        Ok(self.users.iter().find(|u| u.uid == uid).cloned())
+    }
+
+    fn group_by_name(&self, name: &str) -> Result<Option<Group>> {
+        self.check_groups()?;
+        Ok(self.group_db.iter().find(|g| g.name == name).cloned())
+    }
+
+    fn group_by_gid(&self, gid: Gid) -> Result<Option<Group>> {
+        self.check_groups()?;
+        Ok(self.group_db.iter().find(|g| g.gid == gid).cloned())
+    }
+
+    fn supplementary_groups(&self, user: &User) -> Result<Vec<Group>> {
+        self.check_groups()?;
+        Ok(self
+            .group_db
+            .iter()
+            .filter(|g| g.mem.contains(&user.name))
+            .cloned()
+            .collect())
+    }
+
+    fn file_info(&self, path: &Path) -> Result<FileInfo> {
+        self.files
+            .borrow()
+            .get(path)
+            .map(|f| f.info)
+            .ok_or(does_not_exist(path))
+    }
+
+    // Directories don't have to be added to be read, they're just the parents of files
+    fn read_dir(&self, path: &Path) -> Result<Vec<PathBuf>> {
+        Ok(self
+            .files
+            .borrow()
+            .keys()
+            .filter(|p| p.parent() == Some(path))
+            .cloned()
+            .collect())
+    }
+
+    fn read_file(&self, path: &Path) -> Result<String> {
+        self.files
+            .borrow()
+            .get(path)
+            .map(|f| f.content.clone())
+            .ok_or(does_not_exist(path))
+    }
+
+    // Files are owned by the euid, like on a real system
+    fn write_file(&self, path: &Path, content: &str, mode: u32) -> Result<()> {
+        self.files.borrow_mut().insert(
+            path.to_path_buf(),
+            TestFile {
+                content: content.to_string(),
+                info: FileInfo {
+                    owner: self.geteuid(),
+                    mode,
+                    is_dir: false,
+                },
+            },
+        );
+        Ok(())
+    }
+
+    // Only the directory itself is added, as its parents don't have to exist to be read
+    fn create_dir(&self, path: &Path, mode: u32) -> Result<()> {
+        let mut files = self.files.borrow_mut();
+        let dir = files.entry(path.to_path_buf()).or_insert(TestFile {
+            content: String::new(),
+            info: FileInfo {
+                owner: self.geteuid(),
+                mode,
+                is_dir: true,
+            },
+        });
+        if !dir.info.is_dir {
+            return Err(Error::new(
+                ErrorKind::Io,
+                format!("{} is not a directory", path.display()),
+            ));
+        }
+        dir.info.mode = mode;
+        Ok(())
+    }
+
+    fn create_file(&self, path: &Path, content: &str, mode: u32) -> Result<()> {
+        if self.files.borrow().contains_key(path) {
+            return Err(Error::new(
+                ErrorKind::AlreadyExists,
+                format!("{} already exists", path.display()),
+            ));
+        }
+        self.write_file(path, content, mode)
+    }
+
+    fn remove_file(&self, path: &Path) -> Result<()> {
+        self.files
+            .borrow_mut()
+            .remove(path)
+            .map(|_| ())
+            .ok_or(does_not_exist(path))
+    }
+
+    fn remove_dir_all(&self, path: &Path) -> Result<()> {
+        let mut files = self.files.borrow_mut();
+        let len = files.len();
+        files.retain(|p, _| !p.starts_with(path));
+
+        match files.len() < len {
+            true => Ok(()),
+            false => Err(does_not_exist(path)),
+        }
+    }
+
+    fn random_bytes(&self, buf: &mut [u8]) -> Result<()> {
+        let mut next = self.random.borrow_mut();
+        for b in buf {
+            *b = *next;
+            *next = next.wrapping_add(1);
+        }
+        Ok(())
+    }
+
     fn is_root(&self) -> bool {
         self.uid.borrow().is_root() || self.euid.borrow().is_root()
     }
@@ -168,15 +458,22 @@ impl Backend for TestBackend {
 
     fn switch_final(&self) -> Result<()> {
         self.elevate()?;
+        self.initgroups(&self.target_name, self.target_gid)?;
+        self.setgid(self.target_gid)?;
         self.setuid(self.target)
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use nix::unistd::Uid;
+    use std::path::Path;
+
+    use nix::unistd::{Gid, Uid};
 
-    use crate::backend::{Backend, testing::TestBackend};
+    use crate::backend::{
+        Backend,
+        testing::{TestBackend, test_group, test_user},
+    };
 
     #[test]
     fn set_euid() {
@@ -218,4 +515,94 @@ mod tests {
         backend.setuid(uid).unwrap();
         assert!(!backend.is_root());
     }
+
+    #[test]
+    fn switch_final() {
+        let backend = TestBackend::default();
+        backend.switch_final().unwrap();
+
+        assert!(backend.getuid().is_root());
+        assert_eq!(backend.getgid(), Gid::from_raw(0));
+        assert_eq!(*backend.groups.borrow(), [Gid::from_raw(0)]);
+
+        let postgres = test_user("postgres", 1001, 1001);
+        let backend = TestBackend::default()
+            .with_user(postgres.clone())
+            .with_group(test_group("webapps", 30, &["postgres"]))
+            .with_target(&postgres, postgres.gid);
+        backend.switch_final().unwrap();
+
+        assert_eq!(backend.getuid(), Uid::from_raw(1001));
+        assert_eq!(backend.getgid(), Gid::from_raw(1001));
+        assert_eq!(
+            *backend.groups.borrow(),
+            [Gid::from_raw(1001), Gid::from_raw(30)]
+        );
+    }
+
+    #[test]
+    fn user_database() {
+        let backend = TestBackend::default()
+            .with_user(test_user("alice", 1000, 1000))
+            .with_group(test_group("admins", 20, &["alice"]));
+
+        let alice = backend.user_by_name("alice").unwrap().unwrap();
+        assert_eq!(alice.uid, Uid::from_raw(1000));
+        assert_eq!(
+            backend.user_by_uid(Uid::from_raw(1000)).unwrap(),
+            Some(alice.clone())
+        );
+        assert!(backend.user_by_name("bob").unwrap().is_none());
+        assert_eq!(
+            backend
+                .group_by_gid(Gid::from_raw(20))
+                .unwrap()
+                .unwrap()
+                .name,
+            "admins"
+        );
+
+        let groups = backend.supplementary_groups(&alice).unwrap();
+        assert_eq!(
+            groups.iter().map(|g| g.gid).collect::<Vec<_>>(),
+            [Gid::from_raw(20)]
+        );
+
+        backend.initgroups("alice", alice.gid).unwrap();
+        assert_eq!(
+            *backend.groups.borrow(),
+            [Gid::from_raw(1000), Gid::from_raw(20)]
+        );
+    }
+
+    #[test]
+    fn files() {
+        let backend = TestBackend::default()
+            .with_file("/etc/udo/config.toml", "[display]", Uid::from_raw(0), 0o644)
+            .with_file("/etc/udo/config.d/a.toml", "", Uid::from_raw(0), 0o644);
+
+        let dir = backend.read_dir(Path::new("/etc/udo")).unwrap();
+        assert_eq!(dir, [Path::new("/etc/udo/config.toml")]);
+        assert_eq!(
+            backend
+                .read_file(Path::new("/etc/udo/config.toml"))
+                .unwrap(),
+            "[display]"
+        );
+
+        backend
+            .remove_file(Path::new("/etc/udo/config.toml"))
+            .unwrap();
+        assert!(
+            backend
+                .read_file(Path::new("/etc/udo/config.toml"))
+                .is_err()
+        );
+        assert!(
+            backend
+                .remove_file(Path::new("/etc/udo/config.toml"))
+                .is_err()
+        );
+        assert!(backend.read_dir(Path::new("/etc/udo")).unwrap().is_empty());
+    }
 }
@@ -1,36 +1,112 @@
 use std::{
-    fs::{self, File, Permissions},
-    io::{Write, stdin},
-    os::{fd::AsFd, unix::fs::PermissionsExt},
-    path::PathBuf,
+    cmp::Reverse,
+    fmt::Display,
+    io::stdin,
+    os::fd::AsFd,
+    path::{Path, PathBuf},
+    time::Duration,
 };
 
 const CACHE_DIR: &str = "/var/run/udo";
+/// The prefix of the key files, which are suffixed with the boot id they're for. Usernames can't
+/// start with a dot, so they can't clash with a cache directory
+const KEY_PREFIX: &str = ".key-";
+const KEY_LEN: usize = 32;
+/// The most entries kept for a user, e.g. for each of their terminals. Past this, the oldest
+/// entries are removed
+const MAX_ENTRIES: usize = 16;
 
-use anyhow::Result;
+use anyhow::{Result, anyhow, bail};
+use hmac::{Hmac, Mac};
 use nix::{
-    sys::time::TimeValLike,
     time::{ClockId, clock_gettime},
     unistd::{User, getppid, ttyname},
 };
 use serde::{Deserialize, Serialize};
+use sha2::Sha256;
 
-use crate::{backend::Backend, config::Config, run::Run};
+use crate::{
+    backend::{Backend, ErrorKind, FileInfo},
+    cache::session::Session,
+    config::Config,
+    run::Run,
+};
+
+mod session;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// The clock cache entries are timestamped with. It counts from boot, including time spent
+/// suspended, so changing the system time doesn't extend a login
+#[cfg(target_os = "linux")]
+const CLOCK: ClockId = ClockId::CLOCK_BOOTTIME;
+#[cfg(target_os = "macos")]
+const CLOCK: ClockId = ClockId::CLOCK_MONOTONIC;
 
 #[derive(Debug, Clone)]
 pub struct Cache {
     dir: PathBuf,
 }
 
-pub fn get_cache_id(user: &User) -> Result<String> {
-    let uid = user.uid;
+/// CacheScope is what a login is cached for. It's set with `security.cache_scope`
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CacheScope {
+    /// Logins are cached for the terminal they happened in
+    #[default]
+    Tty,
+    /// Logins are cached for the process udo was run from, usually a shell
+    Ppid,
+    /// Logins are cached for every session of the user
+    Global,
+    /// Logins aren't cached
+    None,
+}
+
+impl Display for CacheScope {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::Tty => "tty",
+            Self::Ppid => "ppid",
+            Self::Global => "global",
+            Self::None => "none",
+        })
+    }
+}
+
+impl CacheScope {
+    /// Gets the scope logins can actually be cached in. Without a tty, logins are cached for the
+    /// parent process instead
+    pub fn resolve(self) -> Self {
+        match self {
+            Self::Tty if get_tty().is_err() => Self::Ppid,
+            scope => scope,
+        }
+    }
+}
+
+/// Gets the name of the tty on stdin, e.g. `pts_0` for `/dev/pts/0`
+fn get_tty() -> Result<String> {
     let stdin = stdin();
-    let stdin_fd = stdin.as_fd();
-    let tty_path = ttyname(stdin_fd)?;
-    let tty = tty_path.file_name().unwrap_or_default().to_string_lossy();
-    let pid = getppid();
+    let path = ttyname(stdin.as_fd())?;
+    let name = path.strip_prefix("/dev").unwrap_or(&path);
 
-    Ok(format!("{uid}-{tty}-{pid}"))
+    Ok(name
+        .to_string_lossy()
+        .trim_start_matches('/')
+        .replace('/', "_"))
+}
+
+/// Gets the id of the cache entry of `user` in `scope`, or [None] if logins aren't cached
+pub fn get_cache_id(user: &User, scope: CacheScope) -> Result<Option<String>> {
+    let uid = user.uid;
+
+    Ok(match scope {
+        CacheScope::Tty => Some(format!("{uid}-tty-{}", get_tty()?)),
+        CacheScope::Ppid => Some(format!("{uid}-ppid-{}", getppid())),
+        CacheScope::Global => Some(format!("{uid}-global")),
+        CacheScope::None => None,
+    })
 }
 
 pub fn get_cache_dir(user: &User) -> PathBuf {
@@ -42,105 +118,654 @@ pub fn get_cache_dir(user: &User) -> PathBuf {
 pub fn create_cache_dir(user: &User, backend: &dyn Backend) -> Result<PathBuf> {
     let dir = get_cache_dir(user);
     backend.elevate()?;
-    if fs::exists(&dir)? {
-        let md = fs::metadata(&dir)?;
-        if md.is_dir() {
-            return Ok(dir);
-        }
-    }
-
-    fs::create_dir_all(&dir)?;
-    let parent_default = PathBuf::from(CACHE_DIR);
-    let parent = dir.parent().unwrap_or(&parent_default);
-    fs::set_permissions(parent, Permissions::from_mode(0o700))?;
-    fs::set_permissions(&dir, Permissions::from_mode(0o700))?;
-
+    let res = backend
+        .create_dir(Path::new(CACHE_DIR), 0o700)
+        .and_then(|_| backend.create_dir(&dir, 0o700));
     backend.restore()?;
+    res?;
 
     Ok(dir)
 }
 
-pub fn write_entry(user: &User, entry: CacheEntry, backend: &dyn Backend) -> Result<()> {
-    let id = get_cache_id(user)?;
+/// Writes the entry for the current session, pruning the other entries of `user` afterwards
+pub fn write_entry(
+    user: &User,
+    entry: CacheEntry,
+    timeout: Duration,
+    backend: &dyn Backend,
+) -> Result<()> {
+    let Some(id) = get_cache_id(user, entry.scope)? else {
+        return Ok(());
+    };
     let mut path = get_cache_dir(user);
     path.push(id);
 
-    let mut buf = toml::ser::Buffer::new();
-    let se = toml::Serializer::new(&mut buf);
-    let out = entry.serialize(se)?;
-
     backend.elevate()?;
-    let mut file = File::create(path)?;
-    file.write_all(out.to_string().as_bytes())?;
+    let res = get_key(&entry.session.boot_id, backend).and_then(|key| {
+        backend.write_file(&path, &entry.to_signed(&key)?, 0o600)?;
+        // Pruning is opportunistic, so failing to doesn't fail the login
+        let _ = prune_entries(user, &entry, timeout, backend);
+        Ok(())
+    });
     backend.restore()?;
 
-    Ok(())
+    res
 }
 
 pub fn check_cache(run: &mut Run, config: &Config) -> Result<bool> {
-    let id = get_cache_id(&run.user)?;
+    Ok(cache_remaining(run, config)?.is_some())
+}
+
+/// Gets how much longer the login of the user lasts in the current scope, or [None] if they aren't
+/// logged in
+pub fn cache_remaining(run: &Run, config: &Config) -> Result<Option<Duration>> {
+    let scope = config.security.cache_scope.resolve();
+    let Some(id) = get_cache_id(&run.user, scope)? else {
+        return Ok(None);
+    };
     let mut full = get_cache_dir(&run.user);
     full.push(id);
 
-    let time = clock_gettime(ClockId::CLOCK_REALTIME)?;
+    let current = CacheEntry::try_from(run)?;
 
     run.backend.elevate()?;
-    if !full.exists() || full.is_dir() {
-        return Ok(false);
+    let _ = prune_entries(
+        &run.user,
+        &current,
+        config.security.timeout,
+        run.backend.as_ref(),
+    );
+    let entry = read_entry(&full, &current.session.boot_id, run.backend.as_ref());
+    run.backend.restore()?;
+
+    Ok(entry?.and_then(|e| e.remaining(&current, config.security.timeout)))
+}
+
+/// Removes the entries of `user` which have expired at the time of `current`, as well as entries
+/// which can't be read, then the oldest entries past [MAX_ENTRIES]. It must be called while
+/// elevated
+pub fn prune_entries(
+    user: &User,
+    current: &CacheEntry,
+    timeout: Duration,
+    backend: &dyn Backend,
+) -> Result<()> {
+    let paths = match backend.read_dir(&get_cache_dir(user)) {
+        Ok(p) => p,
+        Err(e) if matches!(e.kind, ErrorKind::DoesNotExist) => return Ok(()),
+        Err(e) => return Err(e.into()),
+    };
+
+    let mut kept = Vec::new();
+    for path in paths {
+        // Entries are only read to find when they were written, so they don't need verifying
+        let entry = backend
+            .read_file(&path)
+            .ok()
+            .and_then(|c| toml::from_str::<SignedEntry>(&c).ok());
+        match entry {
+            Some(e) if e.entry.time_left(current, timeout).is_some() => {
+                kept.push((e.entry.timestamp, path))
+            }
+            _ => backend.remove_file(&path)?,
+        }
     }
 
-    let content = fs::read_to_string(full)?;
-    let entry = CacheEntry::from_content(&content)?;
-    run.backend.restore()?;
+    kept.sort_by_key(|(timestamp, _)| Reverse(*timestamp));
+    for (_, path) in kept.iter().skip(MAX_ENTRIES) {
+        backend.remove_file(path)?;
+    }
 
-    let time_valid = time.num_minutes() - entry.timestamp < config.security.timeout;
-    let user_valid = entry.uid == run.do_as.uid.as_raw();
+    Ok(())
+}
 
-    Ok(time_valid && user_valid)
+/// Reads and verifies the entry at `path` with the key of the boot `boot_id`, returning [None] if
+/// there isn't one
+fn read_entry(path: &Path, boot_id: &str, backend: &dyn Backend) -> Result<Option<CacheEntry>> {
+    match file_info(path, backend)? {
+        Some(info) if !info.is_dir => {}
+        _ => return Ok(None),
+    }
+
+    let content = backend.read_file(path)?;
+    Ok(Some(CacheEntry::from_signed(
+        &content,
+        &get_key(boot_id, backend)?,
+    )?))
+}
+
+fn key_path(boot_id: &str) -> PathBuf {
+    Path::new(CACHE_DIR).join(format!("{KEY_PREFIX}{boot_id}"))
+}
+
+/// Gets the key cache entries are signed with in the boot `boot_id`. A new key is generated by
+/// the first run of every boot, which removes the keys of previous boots. The key is created
+/// exclusively, so concurrent runs all use whichever key was created first. It must be called
+/// while elevated
+fn get_key(boot_id: &str, backend: &dyn Backend) -> Result<Vec<u8>> {
+    let path = key_path(boot_id);
+    let mut key = vec![0; KEY_LEN];
+    backend.random_bytes(&mut key)?;
+    backend.create_dir(Path::new(CACHE_DIR), 0o700)?;
+
+    match backend.create_file(&path, &to_hex(&key), 0o600) {
+        Ok(()) => {
+            // Old keys can't verify anything anymore, so failing to remove them is fine
+            let _ = remove_old_keys(&path, backend);
+            return Ok(key);
+        }
+        Err(e) if matches!(e.kind, ErrorKind::AlreadyExists) => {}
+        Err(e) => return Err(e.into()),
+    }
+
+    let info = backend.file_info(&path)?;
+    if !info.owner.is_root() || info.mode & 0o077 != 0 {
+        bail!(
+            "{} must be owned by root and only readable by root",
+            path.display()
+        );
+    }
+
+    from_hex(backend.read_file(&path)?.trim())
+        .filter(|k| k.len() == KEY_LEN)
+        .ok_or(anyhow!("{} is not a valid key", path.display()))
+}
+
+/// Removes every key other than the one at `current`
+fn remove_old_keys(current: &Path, backend: &dyn Backend) -> Result<()> {
+    for path in backend.read_dir(Path::new(CACHE_DIR))? {
+        let is_key = path
+            .file_name()
+            .is_some_and(|n| n.to_string_lossy().starts_with(KEY_PREFIX));
+        if is_key && path != current {
+            backend.remove_file(&path)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Gets the info of the file at `path`, or [None] if it doesn't exist
+fn file_info(path: &Path, backend: &dyn Backend) -> Result<Option<FileInfo>> {
+    match backend.file_info(path) {
+        Ok(info) => Ok(Some(info)),
+        Err(e) if matches!(e.kind, ErrorKind::DoesNotExist) => Ok(None),
+        Err(e) => Err(e.into()),
+    }
 }
 
 pub fn clear_cache(user: &User, backend: &dyn Backend) -> Result<()> {
     let dir = get_cache_dir(user);
 
     backend.elevate()?;
-    if dir.exists() && dir.is_dir() {
-        fs::remove_dir_all(&dir)?;
-    }
+    let res = file_info(&dir, backend).and_then(|info| match info {
+        Some(info) if info.is_dir => Ok(backend.remove_dir_all(&dir)?),
+        _ => Ok(()),
+    });
     backend.restore()?;
-    Ok(())
+
+    res
+}
+
+/// Clears the cache of every user, returning the number of users whose cache was cleared. The
+/// key is kept, as it's only regenerated on boot
+pub fn clear_all_caches(backend: &dyn Backend) -> Result<usize> {
+    backend.elevate()?;
+    let res = (|| {
+        let paths = match backend.read_dir(Path::new(CACHE_DIR)) {
+            Ok(p) => p,
+            Err(e) if matches!(e.kind, ErrorKind::DoesNotExist) => return Ok(0),
+            Err(e) => return Err(e.into()),
+        };
+
+        let mut cleared = 0;
+        for path in paths {
+            if file_info(&path, backend)?.is_some_and(|i| i.is_dir) {
+                backend.remove_dir_all(&path)?;
+                cleared += 1;
+            }
+        }
+        Ok(cleared)
+    })();
+    backend.restore()?;
+
+    res
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct CacheEntry {
+    /// When the entry was written, in seconds since boot
     timestamp: i64,
     uid: u32,
+    /// The scope the entry was written for. Entries are only valid in the same scope
+    scope: CacheScope,
+    /// The session the entry was written in. Entries are only valid in the same session
+    session: Session,
+}
+
+/// SignedEntry is how a [CacheEntry] is stored, with a MAC of the entry so it can't be forged
+#[derive(Serialize, Deserialize)]
+struct SignedEntry {
+    mac: String,
+    entry: CacheEntry,
 }
 
 impl CacheEntry {
-    pub fn new(timestamp: i64, uid: u32) -> Self {
-        Self { timestamp, uid }
+    pub fn new(timestamp: i64, uid: u32, scope: CacheScope, session: Session) -> Self {
+        Self {
+            timestamp,
+            uid,
+            scope,
+            session,
+        }
     }
 
-    pub fn from_content(content: &str) -> Result<Self> {
-        let de = toml::Deserializer::parse(content)?;
-        Ok(Self::deserialize(de)?)
+    /// Gets how much longer the entry is valid for the `current` run, or [None] if it isn't. It's
+    /// only valid for the same user, scope, and session. Entries from the future are never valid,
+    /// and the session includes the boot id, so entries from a previous boot aren't either
+    pub fn remaining(&self, current: &CacheEntry, timeout: Duration) -> Option<Duration> {
+        let remaining = self.time_left(current, timeout)?;
+        let valid = self.uid == current.uid
+            && self.scope == current.scope
+            && self.session == current.session;
+
+        valid.then_some(remaining)
+    }
+
+    /// Gets how much longer the entry lasts at the time of `current`, in whatever session it was
+    /// written in. Entries from another boot have always expired
+    fn time_left(&self, current: &CacheEntry, timeout: Duration) -> Option<Duration> {
+        let elapsed = u64::try_from(current.timestamp - self.timestamp).ok()?;
+        let remaining = timeout.as_secs().checked_sub(elapsed).filter(|r| *r > 0)?;
+
+        (self.session.boot_id == current.session.boot_id).then_some(Duration::from_secs(remaining))
+    }
+
+    pub fn scope(&self) -> CacheScope {
+        self.scope
+    }
+
+    /// Serializes the entry along with its MAC under `key`
+    pub fn to_signed(&self, key: &[u8]) -> Result<String> {
+        let signed = SignedEntry {
+            mac: to_hex(&self.mac(key)?.finalize().into_bytes()),
+            entry: self.clone(),
+        };
+
+        Ok(toml::to_string(&signed)?)
+    }
+
+    /// Deserializes an entry, failing if its MAC isn't valid under `key`
+    pub fn from_signed(content: &str, key: &[u8]) -> Result<Self> {
+        let signed = toml::from_str::<SignedEntry>(content)?;
+        let mac = from_hex(&signed.mac).ok_or(anyhow!("Cache entry has an invalid MAC"))?;
+        signed
+            .entry
+            .mac(key)?
+            .verify_slice(&mac)
+            .map_err(|_| anyhow!("Cache entry has an invalid MAC"))?;
+
+        Ok(signed.entry)
+    }
+
+    fn mac(&self, key: &[u8]) -> Result<HmacSha256> {
+        let mut mac = HmacSha256::new_from_slice(key)?;
+        mac.update(toml::to_string(self)?.as_bytes());
+        Ok(mac)
     }
 }
 
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
 impl TryFrom<&Run<'_>> for CacheEntry {
     type Error = anyhow::Error;
 
     fn try_from(run: &Run) -> std::result::Result<Self, Self::Error> {
-        let time = clock_gettime(ClockId::CLOCK_REALTIME)?;
-        Ok(CacheEntry::new(time.num_minutes(), run.do_as.uid.as_raw()))
+        let time = clock_gettime(CLOCK)?;
+        let scope = run.config.security.cache_scope.resolve();
+        Ok(CacheEntry::new(
+            time.tv_sec(),
+            run.do_as.uid.as_raw(),
+            scope,
+            Session::current(scope)?,
+        ))
     }
 }
 
-impl TryFrom<&mut Run<'_>> for CacheEntry {
-    type Error = anyhow::Error;
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use std::path::Path;
+
+    use nix::unistd::Uid;
+
+    use crate::{
+        backend::{
+            Backend,
+            testing::{TestBackend, test_user},
+        },
+        cache::{
+            CACHE_DIR, CacheEntry, CacheScope, MAX_ENTRIES, clear_all_caches, clear_cache,
+            create_cache_dir, get_cache_id, get_key, key_path, prune_entries, read_entry,
+            session::Session, to_hex, write_entry,
+        },
+        config::Config,
+    };
+
+    const KEY: [u8; 32] = [7; 32];
+    const TIMEOUT: Duration = Duration::from_secs(10);
+
+    fn session(boot_id: &str, parent_start: u64) -> Session {
+        Session {
+            boot_id: boot_id.into(),
+            parent_start: Some(parent_start),
+            ..Default::default()
+        }
+    }
+
+    fn valid(entry: &CacheEntry, current: &CacheEntry) -> bool {
+        entry.remaining(current, TIMEOUT).is_some()
+    }
+
+    fn entry(timestamp: i64, uid: u32, scope: CacheScope, session: Session) -> CacheEntry {
+        CacheEntry::new(timestamp, uid, scope, session)
+    }
+
+    #[test]
+    fn entry_validity() {
+        let entry = entry(100, 0, CacheScope::Ppid, session("boot", 42));
+        let now = |uid, scope, session| self::entry(105, uid, scope, session);
+
+        assert!(valid(
+            &entry,
+            &now(0, CacheScope::Ppid, session("boot", 42))
+        ));
+        assert!(!valid(
+            &entry,
+            &now(0, CacheScope::Global, session("boot", 42))
+        ));
+        assert!(!valid(
+            &entry,
+            &now(1000, CacheScope::Ppid, session("boot", 42))
+        ));
+        // A recycled parent pid, or a reboot, invalidates the entry
+        assert!(!valid(
+            &entry,
+            &now(0, CacheScope::Ppid, session("boot", 43))
+        ));
+        assert!(!valid(
+            &entry,
+            &now(0, CacheScope::Ppid, session("reboot", 42))
+        ));
+    }
+
+    #[test]
+    fn tty_sessions() {
+        let tty = |sid, leader_start| Session {
+            boot_id: "boot".into(),
+            tty: Some(34816),
+            sid: Some(sid),
+            leader_start,
+            ..Default::default()
+        };
+        let entry = entry(100, 0, CacheScope::Tty, tty(4242, Some(7)));
+        let now = |session| self::entry(105, 0, CacheScope::Tty, session);
+
+        assert!(valid(&entry, &now(tty(4242, Some(7)))));
+        assert!(!valid(&entry, &now(tty(4243, Some(7)))));
+        // A new session reusing the id on the same tty has a leader which started later
+        assert!(!valid(&entry, &now(tty(4242, Some(9)))));
+        assert!(!valid(&entry, &now(tty(4242, None))));
+    }
+
+    #[test]
+    fn entry_expiry() {
+        let entry = entry(100, 0, CacheScope::Global, session("boot", 42));
+        let at = |timestamp| self::entry(timestamp, 0, CacheScope::Global, session("boot", 42));
+
+        assert!(valid(&entry, &at(100)));
+        assert!(valid(&entry, &at(109)));
+        assert!(!valid(&entry, &at(110)));
+        // The clock can't go backwards, so an entry from the future has been tampered with
+        assert!(!valid(&entry, &at(99)));
+
+        assert_eq!(
+            entry.remaining(&at(104), TIMEOUT),
+            Some(Duration::from_secs(6))
+        );
+        assert_eq!(entry.remaining(&at(110), TIMEOUT), None);
+    }
+
+    #[test]
+    fn signed_entries() {
+        let entry = entry(100, 0, CacheScope::Ppid, session("boot", 42));
+        let signed = entry.to_signed(&KEY).unwrap();
+        assert_eq!(CacheEntry::from_signed(&signed, &KEY).unwrap(), entry);
+
+        assert!(CacheEntry::from_signed(&signed, &[8; 32]).is_err());
+        let tampered = signed.replace("uid = 0", "uid = 1000");
+        assert_ne!(tampered, signed);
+        assert!(CacheEntry::from_signed(&tampered, &KEY).is_err());
+        let unsigned = signed.replace(signed.lines().next().unwrap(), "mac = \"\"");
+        assert!(CacheEntry::from_signed(&unsigned, &KEY).is_err());
+    }
+
+    #[test]
+    fn prune_expired() {
+        let dir = "/var/run/udo/alice";
+        let file = |backend: TestBackend, name: &str, entry: CacheEntry| {
+            let content = entry.to_signed(&KEY).unwrap();
+            backend.with_file(format!("{dir}/{name}"), &content, Uid::from_raw(0), 0o600)
+        };
+        let backend = TestBackend::default();
+        let backend = file(
+            backend,
+            "valid",
+            entry(100, 0, CacheScope::Tty, session("boot", 1)),
+        );
+        let backend = file(
+            backend,
+            "other",
+            entry(95, 0, CacheScope::Ppid, session("boot", 2)),
+        );
+        let backend = file(
+            backend,
+            "expired",
+            entry(80, 0, CacheScope::Tty, session("boot", 1)),
+        );
+        let backend = file(
+            backend,
+            "future",
+            entry(200, 0, CacheScope::Tty, session("boot", 1)),
+        );
+        let backend = file(
+            backend,
+            "reboot",
+            entry(100, 0, CacheScope::Tty, session("old", 1)),
+        );
+        let backend = backend.with_file(format!("{dir}/garbage"), "???", Uid::from_raw(0), 0o600);
+        let backend = backend.with_file("/var/run/udo/bob/1", "", Uid::from_raw(0), 0o600);
+
+        let current = entry(100, 0, CacheScope::Tty, session("boot", 1));
+        prune_entries(&test_user("alice", 1000, 1000), &current, TIMEOUT, &backend).unwrap();
+
+        let mut kept = backend.read_dir(Path::new(dir)).unwrap();
+        kept.sort();
+        assert_eq!(
+            kept,
+            [Path::new(dir).join("other"), Path::new(dir).join("valid")]
+        );
+        // Other users' entries are left alone
+        assert!(backend.read_file(Path::new("/var/run/udo/bob/1")).is_ok());
+    }
+
+    #[test]
+    fn prune_oldest() {
+        let dir = "/var/run/udo/alice";
+        let mut backend = TestBackend::default();
+        for i in 0..MAX_ENTRIES as i64 + 4 {
+            let content = entry(90 + i / 2, 0, CacheScope::Ppid, session("boot", i as u64))
+                .to_signed(&KEY)
+                .unwrap();
+            backend = backend.with_file(format!("{dir}/{i}"), &content, Uid::from_raw(0), 0o600);
+        }
+
+        let current = entry(100, 0, CacheScope::Ppid, session("boot", 0));
+        let timeout = Duration::from_secs(60);
+        prune_entries(&test_user("alice", 1000, 1000), &current, timeout, &backend).unwrap();
+
+        let kept = backend.read_dir(Path::new(dir)).unwrap();
+        assert_eq!(kept.len(), MAX_ENTRIES);
+        // The oldest entries were removed
+        for i in 0..4 {
+            assert!(!kept.contains(&Path::new(dir).join(i.to_string())));
+        }
+    }
+
+    #[test]
+    fn scope_ids() {
+        let user = test_user("alice", 1000, 1000);
+
+        assert_eq!(
+            get_cache_id(&user, CacheScope::Global).unwrap().unwrap(),
+            "1000-global"
+        );
+        assert!(get_cache_id(&user, CacheScope::None).unwrap().is_none());
+        assert!(
+            get_cache_id(&user, CacheScope::Ppid)
+                .unwrap()
+                .unwrap()
+                .starts_with("1000-ppid-")
+        );
+        assert_ne!(CacheScope::Tty.resolve(), CacheScope::None);
+    }
+
+    #[test]
+    fn scope_from_config() {
+        let config: Config = toml::from_str("[security]\ncache_scope = \"global\"").unwrap();
+        assert_eq!(config.security.cache_scope, CacheScope::Global);
+        assert_eq!(Config::default().security.cache_scope, CacheScope::Tty);
+    }
+
+    #[test]
+    fn key_generation() {
+        let root = Uid::from_raw(0);
+        let old = key_path("old");
+        let backend = TestBackend::default()
+            .with_file(&old, &to_hex(&KEY), root, 0o600)
+            .with_file("/var/run/udo/alice/1000-global", "", root, 0o600);
+
+        // The first run of a boot generates its key, and only root can read it
+        let key = get_key("boot", &backend).unwrap();
+        assert_eq!(key, (0..32).collect::<Vec<u8>>());
+        let info = backend.file_info(&key_path("boot")).unwrap();
+        assert_eq!((info.owner, info.mode), (root, 0o600));
+        // The keys of previous boots are removed, but nothing else is
+        assert!(backend.file_info(&old).is_err());
+        assert!(
+            backend
+                .file_info(Path::new("/var/run/udo/alice/1000-global"))
+                .is_ok()
+        );
+
+        // Later runs, including ones which lost the race to create it, read the existing key
+        assert_eq!(get_key("boot", &backend).unwrap(), key);
+    }
+
+    #[test]
+    fn key_permissions() {
+        let root = Uid::from_raw(0);
+        let path = key_path("boot");
+        let key = to_hex(&KEY);
+        let get = |owner, mode| {
+            get_key(
+                "boot",
+                &TestBackend::default().with_file(&path, &key, owner, mode),
+            )
+        };
+        assert_eq!(get(root, 0o600).unwrap(), KEY);
+        assert!(get(root, 0o644).is_err());
+        assert!(get(Uid::from_raw(512), 0o600).is_err());
+
+        let short = TestBackend::default().with_file(&path, "0707", root, 0o600);
+        assert!(get_key("boot", &short).is_err());
+    }
+
+    #[test]
+    fn write_and_read() {
+        let user = test_user("alice", 1000, 1000);
+        let backend = TestBackend::default().with_file(
+            key_path("boot"),
+            &to_hex(&KEY),
+            Uid::from_raw(0),
+            0o600,
+        );
+        let entry = entry(100, 0, CacheScope::Global, session("boot", 0));
+
+        let dir = create_cache_dir(&user, &backend).unwrap();
+        write_entry(&user, entry.clone(), TIMEOUT, &backend).unwrap();
+        // The user is dropped back to their own euid afterwards
+        assert_eq!(backend.geteuid(), Uid::from_raw(512));
+
+        let path = dir.join("1000-global");
+        let info = backend.file_info(&path).unwrap();
+        assert_eq!((info.owner, info.mode), (Uid::from_raw(0), 0o600));
+        assert_eq!(backend.file_info(&dir).unwrap().mode, 0o700);
+        assert_eq!(
+            read_entry(&path, "boot", &backend).unwrap(),
+            Some(entry.clone())
+        );
+        assert_eq!(
+            read_entry(&dir.join("missing"), "boot", &backend).unwrap(),
+            None
+        );
+
+        // Entries signed with another key are rejected
+        let forged = entry.to_signed(&[8; 32]).unwrap();
+        let backend = backend.with_file(&path, &forged, Uid::from_raw(0), 0o600);
+        assert!(read_entry(&path, "boot", &backend).is_err());
+    }
+
+    #[test]
+    fn clear_caches() {
+        let backend = TestBackend::default().with_file(
+            key_path("boot"),
+            &to_hex(&KEY),
+            Uid::from_raw(0),
+            0o600,
+        );
+        let alice = test_user("alice", 1000, 1000);
+        let bob = test_user("bob", 1001, 1001);
+        for user in [&alice, &bob] {
+            let entry = entry(100, 0, CacheScope::Global, session("boot", 0));
+            create_cache_dir(user, &backend).unwrap();
+            write_entry(user, entry, TIMEOUT, &backend).unwrap();
+        }
+
+        clear_cache(&alice, &backend).unwrap();
+        assert!(backend.file_info(Path::new("/var/run/udo/alice")).is_err());
+        assert!(backend.file_info(Path::new("/var/run/udo/bob")).is_ok());
+        // Clearing an empty cache is fine
+        clear_cache(&alice, &backend).unwrap();
 
-    fn try_from(run: &mut Run<'_>) -> std::result::Result<Self, Self::Error> {
-        let time = clock_gettime(ClockId::CLOCK_REALTIME)?;
-        Ok(CacheEntry::new(time.num_minutes(), run.do_as.uid.as_raw()))
+        assert_eq!(clear_all_caches(&backend).unwrap(), 1);
+        assert!(backend.file_info(Path::new("/var/run/udo/bob")).is_err());
+        // The key is kept
+        assert!(backend.file_info(&key_path("boot")).is_ok());
+        assert!(backend.file_info(Path::new(CACHE_DIR)).is_ok());
     }
 }
@@ -0,0 +1,126 @@
+#[cfg(target_os = "macos")]
+use std::ffi::CStr;
+#[cfg(target_os = "linux")]
+use std::fs;
+use std::io::stdin;
+use std::os::fd::AsFd;
+
+use anyhow::{Result, anyhow};
+#[cfg(target_os = "macos")]
+use nix::libc;
+use nix::{
+    sys::stat::fstat,
+    unistd::{Pid, getppid, getsid},
+};
+use serde::{Deserialize, Serialize};
+
+use crate::cache::CacheScope;
+
+/// Session identifies where a login happened, so a [CacheEntry](super::CacheEntry) can't be used
+/// anywhere else. Only the parts relevant to the [CacheScope] of the entry are recorded
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Session {
+    /// The id of the boot the login happened in
+    pub boot_id: String,
+    /// The device number of the tty, in the tty scope
+    pub tty: Option<u64>,
+    /// The session id, in the tty scope
+    pub sid: Option<i32>,
+    /// The start time of the session leader, in the tty scope. This stops a new session reusing
+    /// the id of an old one on the same tty from reusing its login. It's [None] if the leader has
+    /// exited, which a later session with the same id can't match
+    pub leader_start: Option<u64>,
+    /// The start time of the parent process, in the ppid scope. This stops a process reusing the
+    /// pid of the parent from reusing its login
+    pub parent_start: Option<u64>,
+}
+
+impl Session {
+    /// Gets the current session in `scope`
+    pub fn current(scope: CacheScope) -> Result<Self> {
+        let mut session = Self {
+            boot_id: get_boot_id()?,
+            ..Default::default()
+        };
+
+        match scope {
+            CacheScope::Tty => {
+                let sid = getsid(None)?;
+                session.tty = Some(fstat(stdin().as_fd())?.st_rdev as u64);
+                session.sid = Some(sid.as_raw());
+                session.leader_start = get_start_time(sid).ok();
+            }
+            CacheScope::Ppid => session.parent_start = Some(get_start_time(getppid())?),
+            CacheScope::Global | CacheScope::None => {}
+        }
+
+        Ok(session)
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn get_boot_id() -> Result<String> {
+    Ok(fs::read_to_string("/proc/sys/kernel/random/boot_id")?
+        .trim()
+        .to_string())
+}
+
+#[cfg(target_os = "macos")]
+fn get_boot_id() -> Result<String> {
+    let mut buf = [0u8; 64];
+    let mut len = buf.len();
+    let res = unsafe {
+        libc::sysctlbyname(
+            c"kern.bootsessionuuid".as_ptr(),
+            buf.as_mut_ptr().cast(),
+            &mut len,
+            std::ptr::null_mut(),
+            0,
+        )
+    };
+    if res != 0 {
+        return Err(anyhow!("Failed to get boot session id"));
+    }
+
+    Ok(CStr::from_bytes_until_nul(&buf)?
+        .to_string_lossy()
+        .to_string())
+}
+
+/// Gets the start time of a process, in clock ticks since boot
+#[cfg(target_os = "linux")]
+fn get_start_time(pid: Pid) -> Result<u64> {
+    let stat = fs::read_to_string(format!("/proc/{pid}/stat"))?;
+    // The name of the process can contain spaces, so the fields are counted from the end of it.
+    // The start time is the 22nd field, and the first after the name is the 3rd
+    let (_, fields) = stat
+        .rsplit_once(')')
+        .ok_or(anyhow!("Invalid stat for process {pid}"))?;
+
+    fields
+        .split_whitespace()
+        .nth(19)
+        .and_then(|t| t.parse().ok())
+        .ok_or(anyhow!("Invalid stat for process {pid}"))
+}
+
+/// Gets the start time of a process, in microseconds since the epoch
+#[cfg(target_os = "macos")]
+fn get_start_time(pid: Pid) -> Result<u64> {
+    let mut info: libc::proc_bsdinfo = unsafe { std::mem::zeroed() };
+    let size = std::mem::size_of::<libc::proc_bsdinfo>() as libc::c_int;
+    let res = unsafe {
+        libc::proc_pidinfo(
+            pid.as_raw(),
+            libc::PROC_PIDTBSDINFO,
+            0,
+            (&mut info as *mut libc::proc_bsdinfo).cast(),
+            size,
+        )
+    };
+    if res != size {
+        return Err(anyhow!("Failed to get info of process {pid}"));
+    }
+
+    Ok(info.pbi_start_tvsec * 1_000_000 + info.pbi_start_tvusec)
+}
@@ -1,5 +1,16 @@
+use std::{env, path::Path};
+
 use clap::{Arg, ArgAction, Command, command};
 
+use crate::config::CONFIG_PATH;
+
+/// Returns if udo was invoked as `udoedit`, e.g. through a symlink
+pub fn is_udoedit() -> bool {
+    env::args_os()
+        .next()
+        .is_some_and(|arg| Path::new(&arg).file_name().is_some_and(|n| n == "udoedit"))
+}
+
 pub fn get_cli() -> Command {
     command!()
         .disable_help_subcommand(true)
@@ -9,7 +20,7 @@ pub fn get_cli() -> Command {
                 .trailing_var_arg(true)
                 .num_args(0..)
                 .allow_hyphen_values(true)
-                .required_unless_present_any(["clear", "shell", "login"])
+                .required_unless_present_any(["clear", "shell", "login", "list", "check_config", "edit_config", "validate", "cache_status", "clear_all", "clear_user"])
                 .conflicts_with_all(["shell", "login"]),
         )
         .arg(Arg::new("preview").short('p').long("preview").action(ArgAction::SetTrue).help("Preview the actions udo will perform and confirm"))
@@ -36,6 +47,50 @@ pub fn get_cli() -> Command {
                 .num_args(1)
                 .default_value("root"),
         )
+        .arg(
+            Arg::new("group")
+                .short('g')
+                .long("group")
+                .help("The group to run as")
+                .long_help("The primary group to run as. Defaults to the primary group of the target user.")
+                .num_args(1),
+        )
+        .arg(
+            Arg::new("edit")
+                .short('E')
+                .long("edit")
+                .help("Edit files as the target user, using your editor")
+                .long_help("Edit files as the target user. The files are copied to a temporary directory and edited with $VISUAL or $EDITOR as you, then written back if they changed. Equivalent to running udo as udoedit.")
+                .action(ArgAction::SetTrue)
+                .conflicts_with_all(["shell", "login", "list"]),
+        )
+        .arg(
+            Arg::new("list")
+                .short('L')
+                .long("list")
+                .help("List the rules which apply to you, or check if you may run a command")
+                .long_help("List the rules which apply to you. If a command is given, checks whether you may run it instead, exiting with an error if not.")
+                .action(ArgAction::SetTrue)
+                .conflicts_with_all(["shell", "login"]),
+        )
+        .arg(
+            Arg::new("check_config")
+                .long("check-config")
+                .value_name("path")
+                .help("Check a config file for errors, defaulting to the installed config")
+                .long_help("Checks a config file for errors, like invalid rules, unknown users and groups, and rules which are never used. The file is read as you. Defaults to the installed config.")
+                .num_args(0..=1)
+                .default_missing_value(CONFIG_PATH)
+                .exclusive(true),
+        )
+        .arg(
+            Arg::new("edit_config")
+                .long("edit-config")
+                .help("Safely edit the installed config, like visudo")
+                .long_help("Edits the installed config like udoedit, but only installs the edited config if it has no errors. Editing the config is allowed by rules allowing you to edit it with udoedit.")
+                .action(ArgAction::SetTrue)
+                .exclusive(true),
+        )
         .arg(
             Arg::new("clear")
                 .short('c')
@@ -43,6 +98,37 @@ pub fn get_cli() -> Command {
                 .action(ArgAction::SetTrue)
                 .help("Clear the login cache"),
         )
+        .arg(
+            Arg::new("validate")
+                .short('v')
+                .long("validate")
+                .help("Refresh your cached login without running a command")
+                .long_help("Refreshes your cached login, asking for your password if it has expired, without running a command. Like sudo -v.")
+                .action(ArgAction::SetTrue)
+                .conflicts_with_all(["command", "shell", "login"]),
+        )
+        .arg(
+            Arg::new("cache_status")
+                .long("cache-status")
+                .help("Show how much longer your cached login lasts")
+                .action(ArgAction::SetTrue)
+                .exclusive(true),
+        )
+        .arg(
+            Arg::new("clear_all")
+                .long("clear-all")
+                .help("Clear the login cache of every user. Root only")
+                .action(ArgAction::SetTrue)
+                .exclusive(true),
+        )
+        .arg(
+            Arg::new("clear_user")
+                .long("clear-user")
+                .value_name("name")
+                .help("Clear the login cache of another user. Root only")
+                .num_args(1)
+                .exclusive(true),
+        )
         .arg(Arg::new("shell").short('s').long("shell").help("Creates a shell as root, preserving $HOME").action(ArgAction::SetTrue))
         .arg(
             Arg::new("login")
@@ -53,3 +139,13 @@ pub fn get_cli() -> Command {
                 .action(ArgAction::SetTrue),
         )
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::cli::get_cli;
+
+    #[test]
+    fn verify_cli() {
+        get_cli().debug_assert();
+    }
+}
@@ -1,30 +1,62 @@
-use anyhow::Result;
-use std::{fs, io};
-use toml::Deserializer;
+use anyhow::{Result, anyhow, bail};
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+    time::Duration,
+};
 
 use serde::{Deserialize, Serialize};
 
 use crate::{
     authenticate::Rule,
-    output::{self, theme::Theme},
+    backend::{Backend, ErrorKind},
+    cache::CacheScope,
+    output::theme::Theme,
 };
 
-const CONFIG_PATH: &str = "/etc/udo/config.toml";
+pub mod check;
+pub mod duration;
+
+pub const CONFIG_PATH: &str = "/etc/udo/config.toml";
+/// The directory drop-in files with extra rules are read from
+pub const CONFIG_DIR: &str = "/etc/udo/config.d";
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(default)]
 pub struct SecurityConfig {
     pub safe_path: Option<String>,
-    pub timeout: i64,
+    /// How long a login is cached for
+    #[serde(with = "duration")]
+    pub timeout: Duration,
+    /// What a login is cached for
+    pub cache_scope: CacheScope,
     pub tries: usize,
+    /// Variables preserved when running commands and non-login shells
+    pub env_keep: Vec<String>,
+    /// Variables preserved in every environment, including login shells
+    pub env_always_keep: Vec<String>,
+    /// Whether `env_keep` and `env_always_keep` replace the default preserved variables rather
+    /// than extending them
+    pub env_replace_defaults: bool,
+    /// Variables always removed, even with `--preserve-env`
+    pub env_delete: Vec<String>,
+    /// Whether host names in rules also match the fully qualified name of the host, which is
+    /// looked up through DNS
+    pub fqdn: bool,
 }
 
 impl Default for SecurityConfig {
     fn default() -> Self {
         Self {
             safe_path: None,
-            timeout: 10,
+            timeout: Duration::from_secs(10 * 60),
+            cache_scope: CacheScope::default(),
             tries: 3,
+            env_keep: Vec::new(),
+            env_always_keep: Vec::new(),
+            env_replace_defaults: false,
+            env_delete: Vec::new(),
+            fqdn: false,
         }
     }
 }
@@ -53,47 +85,266 @@ impl Default for DisplayConfig {
     }
 }
 
+/// Aliases are named lists of values which can be used in place of a value in a [Rule], like
+/// sudoers aliases. Alias values can't themselves be aliases
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct Aliases {
+    /// Aliases for the users a rule applies to or allows running as, which may include `%group`
+    pub users: BTreeMap<String, Vec<String>>,
+    pub hosts: BTreeMap<String, Vec<String>>,
+    /// Aliases for commands, which may include arguments
+    pub commands: BTreeMap<String, Vec<String>>,
+}
+
+impl Aliases {
+    pub fn users<'a>(&'a self, value: &'a String) -> Vec<&'a String> {
+        Self::expand(&self.users, value)
+    }
+
+    pub fn hosts<'a>(&'a self, value: &'a String) -> Vec<&'a String> {
+        Self::expand(&self.hosts, value)
+    }
+
+    pub fn commands<'a>(&'a self, value: &'a String) -> Vec<&'a String> {
+        Self::expand(&self.commands, value)
+    }
+
+    /// Returns the values of the alias `value` names, or `value` itself if it isn't an alias
+    fn expand<'a>(
+        aliases: &'a BTreeMap<String, Vec<String>>,
+        value: &'a String,
+    ) -> Vec<&'a String> {
+        match aliases.get(value) {
+            Some(values) => values.iter().collect(),
+            None => vec![value],
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone, Default)]
 #[serde(default)]
 pub struct Config {
     pub display: DisplayConfig,
+    pub aliases: Aliases,
     pub rules: Vec<Rule>,
     pub security: SecurityConfig,
 }
 
 impl Config {
-    pub fn read() -> Result<Self> {
-        let mut de: Option<Deserializer> = None;
-        let mut content: Option<String> = None;
-        match fs::read_to_string(CONFIG_PATH) {
-            Ok(f) => content = Some(f),
-            Err(e) => output::error(format!("Failed to read config file ({e})"), false, None),
-        };
+    /// Reads the config, refusing to if the config isn't secure
+    pub fn read(backend: &dyn Backend) -> Result<Self> {
+        let path = Path::new(CONFIG_PATH);
+        check_permissions(path, backend)?;
+
+        // Its directory is only writable by root, so the config can't be replaced once checked
+        let content = backend
+            .read_file(path)
+            .map_err(|e| anyhow!("Failed to read config file ({e})"))?;
+        let mut config = toml::from_str::<Self>(&content)
+            .map_err(|e| anyhow!("Could not parse config file \n{e}"))?;
+
+        config.read_fragments(Path::new(CONFIG_DIR), backend)?;
+        Ok(config)
+    }
 
-        if let Some(c) = &content {
-            match toml::Deserializer::parse(c) {
-                Ok(d) => de = Some(d),
-                Err(e) => {
-                    output::error(format!("Failed to create deserializer ({e})"), false, None)
-                }
-            }
+    /// Appends the rules of each drop-in file in `dir` in lexical order, so they're evaluated
+    /// after the rules in the main config
+    fn read_fragments(&mut self, dir: &Path, backend: &dyn Backend) -> Result<()> {
+        for path in fragment_paths(dir, backend)? {
+            check_permissions(&path, backend)?;
+            let content = backend.read_file(&path)?;
+            let fragment = toml::from_str::<Fragment>(&content)
+                .map_err(|e| anyhow!("Could not parse {}\n{e}", path.display()))?;
+            self.rules.extend(
+                fragment
+                    .rules
+                    .into_iter()
+                    .enumerate()
+                    .map(|(i, rule)| rule.with_fragment(&path, i)),
+            );
         }
 
-        if let Some(de) = de {
-            match Self::deserialize(de) {
-                Ok(c) => Ok(c),
-                Err(e) => Err(io::Error::new(
-                    io::ErrorKind::InvalidData,
-                    format!("Could not parse config file \n{e}"),
-                )
-                .into()),
-            }
-        } else {
-            Err(io::Error::new(
-                io::ErrorKind::InvalidData,
-                "could not read configuration file",
-            )
-            .into())
+        Ok(())
+    }
+}
+
+/// Fragment is a drop-in file in [CONFIG_DIR]. Fragments can only contain rules
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct Fragment {
+    pub rules: Vec<Rule>,
+}
+
+/// Returns the paths of the drop-in files in `dir` in lexical order. Only files ending in `.toml`
+/// are read, so editor backups and package manager leftovers are ignored
+pub fn fragment_paths(dir: &Path, backend: &dyn Backend) -> Result<Vec<PathBuf>> {
+    let entries = match backend.read_dir(dir) {
+        Ok(e) => e,
+        Err(e) if matches!(e.kind, ErrorKind::DoesNotExist) => return Ok(Vec::new()),
+        Err(e) => return Err(e.into()),
+    };
+
+    let mut paths = entries
+        .into_iter()
+        .filter(|p| p.extension().is_some_and(|e| e == "toml"))
+        .collect::<Vec<_>>();
+    paths.sort();
+
+    Ok(paths)
+}
+
+/// Checks that the config file and its directory are owned by root and only writable by root. The
+/// config decides who can become root, so anyone else being able to change it would let them too.
+pub fn check_permissions(path: &Path, backend: &dyn Backend) -> Result<()> {
+    let dir = path.parent().unwrap_or(Path::new("/"));
+    for p in [dir, path] {
+        let info = backend.file_info(p)?;
+        if !info.owner.is_root() {
+            bail!("{} is not owned by root", p.display());
         }
+        if info.mode & 0o022 != 0 {
+            bail!(
+                "{} is writable by users other than root (mode {:o})",
+                p.display(),
+                info.mode
+            );
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use nix::unistd::Uid;
+
+    use crate::{
+        backend::testing::TestBackend,
+        config::{CONFIG_DIR, Config, Fragment, check_permissions, fragment_paths},
+    };
+
+    const PATH: &str = "/etc/udo/config.toml";
+
+    fn config_backend(dir_owner: u32, dir_mode: u32, owner: u32, mode: u32) -> TestBackend {
+        TestBackend::default()
+            .with_file("/etc/udo", "", Uid::from_raw(dir_owner), dir_mode)
+            .with_file(PATH, "", Uid::from_raw(owner), mode)
+    }
+
+    #[test]
+    fn secure_permissions() {
+        let backend = config_backend(0, 0o755, 0, 0o644);
+        assert!(check_permissions(Path::new(PATH), &backend).is_ok());
+    }
+
+    #[test]
+    fn insecure_permissions() {
+        // Owned by another user
+        let backend = config_backend(0, 0o755, 512, 0o644);
+        assert!(check_permissions(Path::new(PATH), &backend).is_err());
+        // World writable
+        let backend = config_backend(0, 0o755, 0, 0o646);
+        assert!(check_permissions(Path::new(PATH), &backend).is_err());
+        // Group writable directory, which lets the config be replaced
+        let backend = config_backend(0, 0o775, 0, 0o644);
+        assert!(check_permissions(Path::new(PATH), &backend).is_err());
+    }
+
+    #[test]
+    fn read_config() {
+        let read = |content: &str, mode: u32| {
+            let backend = TestBackend::default()
+                .with_file("/etc/udo", "", Uid::from_raw(0), 0o755)
+                .with_file(PATH, content, Uid::from_raw(0), mode);
+            Config::read(&backend)
+        };
+
+        let config = read("[security]\ntries = 5", 0o644).unwrap();
+        assert_eq!(config.security.tries, 5);
+        assert!(read("[security]\ntries = 5", 0o666).is_err());
+        assert!(read("[security", 0o644).is_err());
+    }
+
+    #[test]
+    fn missing_config() {
+        let backend = TestBackend::default();
+        assert!(check_permissions(Path::new(PATH), &backend).is_err());
+    }
+
+    #[test]
+    fn fragment_order() {
+        let root = Uid::from_raw(0);
+        let dir = Path::new(CONFIG_DIR);
+        let mut backend = TestBackend::default();
+        for name in ["20-b.toml", "10-a.toml", "30-c.toml~", "README"] {
+            backend = backend.with_file(dir.join(name), "", root, 0o644);
+        }
+
+        assert_eq!(
+            fragment_paths(dir, &backend).unwrap(),
+            [dir.join("10-a.toml"), dir.join("20-b.toml")]
+        );
+        assert!(
+            fragment_paths(dir, &TestBackend::default())
+                .unwrap()
+                .is_empty()
+        );
+    }
+
+    #[test]
+    fn read_fragments() {
+        let root = Uid::from_raw(0);
+        let rule =
+            "[[rules]]\ntarget = \"root\"\nhost = \"ALL\"\nuser = \"ALL\"\ncommand = \"ALL\"\n";
+        let backend = TestBackend::default()
+            .with_file(CONFIG_DIR, "", root, 0o755)
+            .with_file(format!("{CONFIG_DIR}/10-a.toml"), rule, root, 0o644)
+            .with_file(
+                format!("{CONFIG_DIR}/20-b.toml"),
+                &format!("{rule}\n{rule}"),
+                root,
+                0o644,
+            );
+
+        let mut config: Config = toml::from_str(rule).unwrap();
+        config
+            .read_fragments(Path::new(CONFIG_DIR), &backend)
+            .unwrap();
+        let positions = config
+            .rules
+            .iter()
+            .enumerate()
+            .map(|(i, r)| r.position(i))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            positions,
+            [
+                "rule 1",
+                "rule 1 of /etc/udo/config.d/10-a.toml",
+                "rule 1 of /etc/udo/config.d/20-b.toml",
+                "rule 2 of /etc/udo/config.d/20-b.toml",
+            ]
+        );
+
+        // Fragments are held to the same permissions as the main config
+        let insecure = backend.with_file(format!("{CONFIG_DIR}/30-c.toml"), rule, root, 0o666);
+        let mut config = Config::default();
+        let err = config
+            .read_fragments(Path::new(CONFIG_DIR), &insecure)
+            .unwrap_err();
+        assert!(err.to_string().contains("30-c.toml"));
+    }
+
+    #[test]
+    fn fragment_only_rules() {
+        let rule =
+            "[[rules]]\ntarget = \"root\"\nhost = \"ALL\"\nuser = \"ALL\"\ncommand = \"ALL\"\n";
+        assert_eq!(toml::from_str::<Fragment>(rule).unwrap().rules.len(), 1);
+
+        let security = format!("[security]\ntries = 10\n\n{rule}");
+        assert!(toml::from_str::<Fragment>(&security).is_err());
     }
 }
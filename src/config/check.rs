@@ -0,0 +1,475 @@
+use std::{fmt::Display, ops::Range, path::Path};
+
+use serde::Deserialize;
+use toml::Spanned;
+
+use crate::{
+    authenticate::Rule,
+    backend::Backend,
+    config::{
+        Aliases, CONFIG_DIR, CONFIG_PATH, Config, Fragment, check_permissions, fragment_paths,
+    },
+    output,
+    run::path::DEFAULT_SAFE_PATH,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    Warning,
+    Error,
+}
+
+/// Diagnostic is a problem found while checking a config file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub message: String,
+    /// The line and column the problem is at, starting from 1
+    pub position: Option<(usize, usize)>,
+}
+
+impl Display for Diagnostic {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.position {
+            Some((line, col)) => write!(f, "{line}:{col}: {}", self.message),
+            None => f.write_str(&self.message),
+        }
+    }
+}
+
+impl Diagnostic {
+    pub fn error<S: ToString>(message: S) -> Self {
+        Self {
+            severity: Severity::Error,
+            message: message.to_string(),
+            position: None,
+        }
+    }
+
+    pub fn warning<S: ToString>(message: S) -> Self {
+        Self {
+            severity: Severity::Warning,
+            message: message.to_string(),
+            position: None,
+        }
+    }
+
+    /// Sets the position of the diagnostic to the start of `span` in `content`
+    pub fn at(mut self, content: &str, span: Option<Range<usize>>) -> Self {
+        self.position = span.map(|s| line_col(content, s.start));
+        self
+    }
+}
+
+/// The positions of the parts of the config that are checked, as [Config] doesn't keep them
+#[derive(Deserialize, Default)]
+#[serde(default)]
+struct Spans {
+    rules: Vec<Spanned<toml::Table>>,
+    security: SecuritySpans,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+struct SecuritySpans {
+    tries: Option<Spanned<toml::Value>>,
+}
+
+/// Checks the content of a config file, returning every problem found. Users and groups are
+/// looked up with `backend`, and so are the drop-in files, which can supply all the rules
+pub fn check_config(content: &str, backend: &dyn Backend) -> Vec<Diagnostic> {
+    let config = match toml::from_str::<Config>(content) {
+        Ok(c) => c,
+        Err(e) => return vec![Diagnostic::error(e.message()).at(content, e.span())],
+    };
+    let spans = toml::from_str::<Spans>(content).unwrap_or_default();
+    let mut diagnostics = Vec::new();
+
+    if config.security.tries == 0 {
+        diagnostics.push(
+            Diagnostic::error("tries is 0, so nobody can authenticate")
+                .at(content, spans.security.tries.as_ref().map(|t| t.span())),
+        );
+    }
+
+    if config.rules.is_empty() && !has_fragment_rules(backend) {
+        diagnostics.push(Diagnostic::warning(format!(
+            "There are no rules, here or in {CONFIG_DIR}, so nobody can use udo"
+        )));
+    }
+
+    let path = config
+        .security
+        .safe_path
+        .as_deref()
+        .unwrap_or(DEFAULT_SAFE_PATH);
+    diagnostics.append(&mut check_rules(
+        content,
+        &config.rules,
+        &spans,
+        path,
+        &config.aliases,
+        backend,
+    ));
+
+    diagnostics
+}
+
+/// Checks if any drop-in file has rules. Files which can't be read or parsed are skipped, as
+/// they're reported when the drop-in files themselves are checked
+fn has_fragment_rules(backend: &dyn Backend) -> bool {
+    fragment_paths(Path::new(CONFIG_DIR), backend)
+        .unwrap_or_default()
+        .iter()
+        .filter_map(|p| backend.read_file(p).ok())
+        .filter_map(|c| toml::from_str::<Fragment>(&c).ok())
+        .any(|f| !f.rules.is_empty())
+}
+
+/// Checks the content of a drop-in file, resolving commands against `path` and expanding the
+/// aliases of the main config
+pub fn check_fragment(
+    content: &str,
+    path: &str,
+    aliases: &Aliases,
+    backend: &dyn Backend,
+) -> Vec<Diagnostic> {
+    let fragment = match toml::from_str::<Fragment>(content) {
+        Ok(f) => f,
+        Err(e) => return vec![Diagnostic::error(e.message()).at(content, e.span())],
+    };
+    let spans = toml::from_str::<Spans>(content).unwrap_or_default();
+
+    check_rules(content, &fragment.rules, &spans, path, aliases, backend)
+}
+
+fn check_rules(
+    content: &str,
+    rules: &[Rule],
+    spans: &Spans,
+    path: &str,
+    aliases: &Aliases,
+    backend: &dyn Backend,
+) -> Vec<Diagnostic> {
+    let mut diagnostics = Vec::new();
+    for (i, rule) in rules.iter().enumerate() {
+        let span = spans.rules.get(i).map(|r| r.span());
+
+        // Each alias value is checked, but a problem with a value is only reported once
+        let mut problems = Vec::new();
+        for diagnostic in rule
+            .expand(aliases)
+            .iter()
+            .flat_map(|r| r.validate(path, backend))
+        {
+            if !problems.contains(&diagnostic) {
+                problems.push(diagnostic);
+            }
+        }
+        for diagnostic in problems {
+            diagnostics.push(Diagnostic {
+                message: format!("rule {}: {}", i + 1, diagnostic.message),
+                ..diagnostic.at(content, span.clone())
+            });
+        }
+
+        // The last matching rule decides, so a rule is never used if a later rule always matches
+        if let Some(j) = (i + 1..rules.len()).find(|&j| rules[j].overrides(rule)) {
+            diagnostics.push(
+                Diagnostic::warning(format!(
+                    "rule {} is never used, as rule {} always overrides it",
+                    i + 1,
+                    j + 1
+                ))
+                .at(content, span),
+            );
+        }
+    }
+
+    diagnostics
+}
+
+/// Checks the config file at `path`, printing any problems found. Returns if the config is valid.
+///
+/// If `path` is the installed config, its permissions and the drop-in files are checked too
+pub fn check_config_file(path: &Path, backend: &dyn Backend, nerd: bool) -> bool {
+    let Some(content) = read_file(path, backend, nerd) else {
+        return false;
+    };
+    let installed = path == Path::new(CONFIG_PATH);
+
+    let mut diagnostics = check_config(&content, backend);
+    // Other configs can be anywhere, as they're installed with --edit-config
+    if installed && let Err(e) = check_permissions(path, backend) {
+        diagnostics.push(Diagnostic::error(e));
+    }
+    print_diagnostics(path, &diagnostics, nerd);
+    let mut valid = !has_errors(&diagnostics);
+
+    if installed {
+        let config = toml::from_str::<Config>(&content).unwrap_or_default();
+        valid &= check_fragments(backend, &config, nerd);
+    }
+
+    if valid {
+        output::info(format!("{} is valid", path.display()), nerd, None);
+    }
+
+    valid
+}
+
+/// Checks each drop-in file against the main config, printing any problems found. Returns if
+/// they're all valid
+fn check_fragments(backend: &dyn Backend, config: &Config, nerd: bool) -> bool {
+    let safe_path = config
+        .security
+        .safe_path
+        .as_deref()
+        .unwrap_or(DEFAULT_SAFE_PATH);
+    let paths = match fragment_paths(Path::new(CONFIG_DIR), backend) {
+        Ok(p) => p,
+        Err(e) => {
+            output::error(e, nerd, None);
+            return false;
+        }
+    };
+
+    let mut valid = true;
+    for path in paths {
+        let Some(content) = read_file(&path, backend, nerd) else {
+            valid = false;
+            continue;
+        };
+
+        let mut diagnostics = check_fragment(&content, safe_path, &config.aliases, backend);
+        if let Err(e) = check_permissions(&path, backend) {
+            diagnostics.push(Diagnostic::error(e));
+        }
+        print_diagnostics(&path, &diagnostics, nerd);
+        valid &= !has_errors(&diagnostics);
+    }
+
+    valid
+}
+
+fn read_file(path: &Path, backend: &dyn Backend, nerd: bool) -> Option<String> {
+    match backend.read_file(path) {
+        Ok(c) => Some(c),
+        Err(e) => {
+            output::error(e, nerd, None);
+            None
+        }
+    }
+}
+
+pub fn print_diagnostics(path: &Path, diagnostics: &[Diagnostic], nerd: bool) {
+    for diagnostic in diagnostics {
+        let message = match diagnostic.position {
+            Some(_) => format!("{}:{diagnostic}", path.display()),
+            None => format!("{}: {diagnostic}", path.display()),
+        };
+        match diagnostic.severity {
+            Severity::Error => output::error(message, nerd, None),
+            Severity::Warning => output::warning(message, nerd, None),
+        }
+    }
+}
+
+pub fn has_errors(diagnostics: &[Diagnostic]) -> bool {
+    diagnostics.iter().any(|d| d.severity == Severity::Error)
+}
+
+/// Converts a byte offset in `content` to a line and column, starting from 1
+fn line_col(content: &str, offset: usize) -> (usize, usize) {
+    let before = &content[..offset.min(content.len())];
+    let line = before.matches('\n').count() + 1;
+    let col = before
+        .rsplit('\n')
+        .next()
+        .unwrap_or_default()
+        .chars()
+        .count()
+        + 1;
+
+    (line, col)
+}
+
+#[cfg(test)]
+mod tests {
+    use nix::unistd::Uid;
+
+    use crate::{
+        backend::testing::TestBackend,
+        config::{
+            Aliases, CONFIG_DIR,
+            check::{Severity, check_config, check_fragment, has_errors},
+        },
+    };
+
+    const RULE: &str = r#"
+[[rules]]
+target = "root"
+host = "ALL"
+user = "ALL"
+command = "ALL"
+"#;
+
+    #[test]
+    fn valid_config() {
+        assert!(check_config(RULE, &TestBackend::default()).is_empty());
+    }
+
+    #[test]
+    fn rules_in_fragments() {
+        let no_rules = |backend| {
+            check_config("[security]\ntries = 3\n", &backend)
+                .iter()
+                .any(|d| d.message.starts_with("There are no rules"))
+        };
+        let root = Uid::from_raw(0);
+        let fragment = format!("{CONFIG_DIR}/10-rules.toml");
+
+        assert!(no_rules(TestBackend::default()));
+        // Drop-in files without rules, or which aren't read, don't count
+        let empty = TestBackend::default().with_file(&fragment, "", root, 0o644);
+        assert!(no_rules(empty));
+        let ignored = TestBackend::default().with_file(
+            format!("{CONFIG_DIR}/10-rules.toml.bak"),
+            RULE,
+            root,
+            0o644,
+        );
+        assert!(no_rules(ignored));
+
+        let backend = TestBackend::default().with_file(&fragment, RULE, root, 0o644);
+        assert!(!no_rules(backend));
+    }
+
+    #[test]
+    fn parse_error_position() {
+        let diagnostics = check_config(
+            "[display]\ncolor = true\n\n[display.theme]\nprompt_color = \"purple\"\n",
+            &TestBackend::default(),
+        );
+
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].severity, Severity::Error);
+        assert_eq!(diagnostics[0].position, Some((5, 16)));
+    }
+
+    #[test]
+    fn zero_tries() {
+        let diagnostics = check_config(
+            &format!("[security]\ntries = 0\n{RULE}"),
+            &TestBackend::default(),
+        );
+
+        assert!(has_errors(&diagnostics));
+        assert_eq!(diagnostics[0].position, Some((2, 9)));
+    }
+
+    #[test]
+    fn invalid_rules() {
+        let config = r#"
+[[rules]]
+target = "udo-no-such-user"
+host = "ALL"
+user = "ALL"
+command = "ALL"
+
+[[rules]]
+target = "root"
+host = "ALL"
+user = "^[a-z$"
+command = "ALL"
+
+[[rules]]
+target = "root"
+host = "ALL"
+user = "ALL"
+command = "udoedit /etc/[nginx"
+"#;
+        let diagnostics = check_config(config, &TestBackend::default());
+
+        assert!(has_errors(&diagnostics));
+        assert!(diagnostics.iter().all(|d| d.position.is_some()));
+        assert!(
+            diagnostics
+                .iter()
+                .any(|d| d.message.contains("udo-no-such-user") && d.position == Some((2, 1)))
+        );
+        assert!(
+            diagnostics
+                .iter()
+                .any(|d| d.message.contains("invalid regex") && d.position == Some((8, 1)))
+        );
+        assert!(
+            diagnostics
+                .iter()
+                .any(|d| d.message.contains("invalid glob") && d.position == Some((14, 1)))
+        );
+    }
+
+    #[test]
+    fn unreachable_rule() {
+        let config = r#"
+[[rules]]
+target = "root"
+host = "ALL"
+user = "ALL"
+command = "/bin/sh -c true"
+
+[[rules]]
+target = "root"
+host = "ALL"
+user = "ALL"
+command = "/bin/sh"
+deny = true
+"#;
+        let diagnostics = check_config(config, &TestBackend::default());
+
+        assert!(!has_errors(&diagnostics));
+        assert_eq!(diagnostics.len(), 1);
+        assert!(diagnostics[0].message.contains("rule 1 is never used"));
+    }
+
+    #[test]
+    fn fragment_errors() {
+        assert!(
+            check_fragment(
+                RULE,
+                "/usr/bin",
+                &Aliases::default(),
+                &TestBackend::default(),
+            )
+            .is_empty()
+        );
+
+        let diagnostics = check_fragment(
+            &format!("[security]\ntries = 10\n{RULE}"),
+            "/usr/bin",
+            &Aliases::default(),
+            &TestBackend::default(),
+        );
+        assert!(has_errors(&diagnostics));
+        assert_eq!(diagnostics[0].position, Some((1, 2)));
+    }
+
+    #[test]
+    fn alias_values() {
+        let config = r#"
+[aliases]
+users.ADMINS = ["root", "udo-no-such-user"]
+
+[[rules]]
+target = "ADMINS"
+host = "ALL"
+user = "ALL"
+command = "ALL"
+"#;
+        let diagnostics = check_config(config, &TestBackend::default());
+
+        assert_eq!(diagnostics.len(), 1);
+        assert!(diagnostics[0].message.contains("udo-no-such-user"));
+    }
+}
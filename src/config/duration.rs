@@ -0,0 +1,122 @@
+//! Serde helpers for durations in the config, which are written like `"90s"`, `"15m"` or
+//! `"1h30m"`. Plain integers are minutes, which is how durations used to be written
+
+use std::time::Duration;
+
+use serde::{Deserialize, Deserializer, Serializer, de::Error};
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Value {
+    Minutes(u64),
+    Text(String),
+}
+
+pub fn serialize<S: Serializer>(duration: &Duration, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&format(*duration))
+}
+
+pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Duration, D::Error> {
+    match Value::deserialize(deserializer)? {
+        Value::Minutes(m) => Ok(Duration::from_secs(m * 60)),
+        Value::Text(t) => parse(&t).map_err(D::Error::custom),
+    }
+}
+
+/// Parses a duration made of numbers followed by `s`, `m` or `h`, like `1h30m`
+pub fn parse(value: &str) -> Result<Duration, String> {
+    let invalid =
+        || format!("invalid duration \"{value}\", expected e.g. \"90s\", \"15m\" or \"1h\"");
+
+    let mut secs = 0u64;
+    let mut number = String::new();
+    for c in value.trim().chars() {
+        if c.is_ascii_digit() {
+            number.push(c);
+            continue;
+        }
+
+        let unit = match c {
+            's' => 1,
+            'm' => 60,
+            'h' => 60 * 60,
+            _ => return Err(invalid()),
+        };
+        let n = number.parse::<u64>().map_err(|_| invalid())?;
+        secs = n
+            .checked_mul(unit)
+            .and_then(|s| secs.checked_add(s))
+            .ok_or_else(invalid)?;
+        number.clear();
+    }
+
+    // Every number needs a unit, and there has to be at least one
+    if !number.is_empty() || value.trim().is_empty() {
+        return Err(invalid());
+    }
+
+    Ok(Duration::from_secs(secs))
+}
+
+/// Formats a duration like `1h30m`, leaving out units which are zero
+pub fn format(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    let parts = [
+        (secs / 3600, 'h'),
+        (secs % 3600 / 60, 'm'),
+        (secs % 60, 's'),
+    ];
+
+    let formatted = parts
+        .iter()
+        .filter(|(n, _)| *n > 0)
+        .map(|(n, unit)| format!("{n}{unit}"))
+        .collect::<String>();
+    match formatted.is_empty() {
+        true => "0s".to_string(),
+        false => formatted,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::config::{
+        Config,
+        duration::{format, parse},
+    };
+
+    #[test]
+    fn durations() {
+        assert_eq!(parse("90s"), Ok(Duration::from_secs(90)));
+        assert_eq!(parse("15m"), Ok(Duration::from_secs(15 * 60)));
+        assert_eq!(parse("1h30m"), Ok(Duration::from_secs(90 * 60)));
+        assert!(parse("15").is_err());
+        assert!(parse("m").is_err());
+        assert!(parse("").is_err());
+        assert!(parse("15 minutes").is_err());
+
+        assert_eq!(format(Duration::from_secs(90)), "1m30s");
+        assert_eq!(format(Duration::from_secs(7200)), "2h");
+        assert_eq!(format(Duration::from_secs(3601)), "1h1s");
+        assert_eq!(format(Duration::ZERO), "0s");
+        assert_eq!(
+            parse(&format(Duration::from_secs(5432))),
+            Ok(Duration::from_secs(5432))
+        );
+    }
+
+    #[test]
+    fn config_timeout() {
+        let timeout = |value: &str| {
+            toml::from_str::<Config>(&format!("[security]\ntimeout = {value}"))
+                .map(|c| c.security.timeout)
+        };
+
+        assert_eq!(timeout("\"90s\"").unwrap(), Duration::from_secs(90));
+        // Integers are minutes
+        assert_eq!(timeout("5").unwrap(), Duration::from_secs(300));
+        assert!(timeout("\"5 minutes\"").is_err());
+    }
+}
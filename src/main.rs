@@ -1,8 +1,14 @@
-use std::process::exit;
+use std::{path::Path, process::exit};
 
 use crossterm::style::force_color_output;
+use nix::unistd::{getuid, setuid};
 
-use crate::{cli::get_cli, config::Config, run::Run};
+use crate::{
+    backend::system::SystemBackend,
+    cli::get_cli,
+    config::{Config, check::check_config_file},
+    run::Run,
+};
 
 mod authenticate;
 mod backend;
@@ -17,11 +23,30 @@ mod user;
 fn main() {
     let cli = get_cli();
     let matches = cli.get_matches();
-    let config = match Config::read() {
+
+    // Checking the config doesn't need it to be valid, so it happens before it's read
+    if let Some(path) = matches.get_one::<String>("check_config") {
+        // Privileges are dropped for good, so this can't be used to read files the user can't
+        if let Err(e) = setuid(getuid()) {
+            output::error(format!("Failed to drop privileges ({e})"), false, None);
+            exit(1)
+        }
+        exit(
+            if check_config_file(Path::new(path), &SystemBackend::default(), false) {
+                0
+            } else {
+                1
+            },
+        )
+    }
+
+    let config = match Config::read(&SystemBackend::default()) {
         Ok(c) => c,
         Err(e) => {
             output::error_with_details("Config error", e, false, None);
-            println!("In future, please consider using udoedit");
+            println!(
+                "In future, please consider using udo --edit-config, which checks the config before installing it"
+            );
             println!(
                 "Use sudo/doas to fix the config file, or chroot into your system from a live system."
             );
@@ -38,11 +63,13 @@ fn main() {
         Ok(mut r) => match r.do_run() {
             Ok(_) => {}
             Err(e) => {
-                output::error_with_details("Could not execute run", e, config.display.nerd, None)
+                output::error_with_details("Could not execute run", e, config.display.nerd, None);
+                exit(1)
             }
         },
         Err(e) => {
-            output::error_with_details("Failed to initialise run", e, config.display.nerd, None)
+            output::error_with_details("Failed to initialise run", e, config.display.nerd, None);
+            exit(1)
         }
     }
 }
@@ -127,6 +127,19 @@ pub fn info<D: Display>(info: D, icon: bool, output: Option<Output>) {
     execute!(output.get_write(), Print(format!("{block} {info}\n")));
 }
 
+pub fn warning<D: Display>(warning: D, icon: bool, output: Option<Output>) {
+    let icon = match icon {
+        true => '',
+        false => '?',
+    };
+
+    let style = ContentStyle::default().on_yellow().black();
+    let block = block(&style, "Warning", &icon.to_string());
+
+    let output = output.unwrap_or(Output::Stderr);
+    let _ = execute!(output.get_write(), Print(format!("{block} {warning}\n")));
+}
+
 pub fn wrong_password(icon: bool, tries: usize) {
     let icon = match icon {
         true => '',
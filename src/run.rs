@@ -1,14 +1,29 @@
-use std::{collections::HashSet, fmt::Display, os};
+use std::{
+    collections::HashSet,
+    fmt::Display,
+    fs, os,
+    path::{Path, PathBuf},
+};
 
 use crate::{
-    authenticate::{AuthResult, authenticate_password, check_action_auth},
+    authenticate::{
+        AuthResult, Decision, authenticate_password, check_action_auth, check_edit_auth,
+        check_validate_auth, get_matching_rules,
+    },
     backend::{Backend, system::SystemBackend},
-    cache::{self, Cache, CacheEntry, check_cache},
-    config::Config,
+    cache::{self, Cache, CacheEntry, CacheScope, check_cache},
+    cli::is_udoedit,
+    config::{CONFIG_PATH, Config, duration},
     output::{self, MultiStyled, Output, prompt_password, wrong_password},
-    run::{env::Env, process::run_process},
-    user::{get_user, get_user_by_id},
+    run::{
+        edit::{edit_config, edit_files, resolve_edit_path},
+        env::{Env, EnvPolicy},
+        path::{DEFAULT_SAFE_PATH, locate_command},
+        process::run_process,
+    },
+    user::{get_group, get_user, get_user_by_id},
 };
+use anyhow::bail;
 use clap::ArgMatches;
 use crossterm::{
     execute,
@@ -17,12 +32,14 @@ use crossterm::{
 };
 use nix::{
     sys::stat::{Mode, stat},
-    unistd::{Uid, User, getuid},
+    unistd::{Group, Uid, User, getuid},
 };
 use std::env as std_env;
 use std::process::exit;
 
+pub mod edit;
 pub mod env;
+pub mod path;
 pub mod process;
 
 #[derive(PartialEq, Eq, Debug, Clone, Copy, Hash, Default)]
@@ -60,6 +77,13 @@ pub enum ActionType {
     Login = 1,
     Shell = 2,
     RunCommand = 3,
+    List = 4,
+    Edit = 5,
+    EditConfig = 6,
+    Validate = 7,
+    CacheStatus = 8,
+    ClearUserCache = 9,
+    ClearAllCaches = 10,
 }
 
 impl Display for ActionType {
@@ -69,6 +93,13 @@ impl Display for ActionType {
             Self::Login => "login_shell",
             Self::Shell => "normal_shell",
             Self::RunCommand => "run_command",
+            Self::List => "list_rules",
+            Self::Edit => "edit_files",
+            Self::EditConfig => "edit_config",
+            Self::Validate => "validate",
+            Self::CacheStatus => "cache_status",
+            Self::ClearUserCache => "clear_user_cache",
+            Self::ClearAllCaches => "clear_all_caches",
         })
     }
 }
@@ -115,19 +146,79 @@ impl Action {
                 ret
             }
             ActionType::Login => {
-                let cmd = run.command.clone();
+                let cmd = run.argv();
+                let exe = run.executable.clone();
                 let mut env = Env::login_env(run);
-                run_process(&cmd.unwrap(), &mut env)
+                run_process(&exe.unwrap(), &cmd, &mut env)
             }
             ActionType::Shell => {
-                let cmd = run.command.clone();
+                let cmd = run.argv();
+                let exe = run.executable.clone();
                 let mut env = Env::non_login_env(run);
-                run_process(&cmd.clone().unwrap(), &mut env)
+                run_process(&exe.unwrap(), &cmd, &mut env)
             }
             ActionType::RunCommand => {
-                let cmd = run.command.clone();
+                let cmd = run.argv();
+                let exe = run.executable.clone();
                 let mut env = Env::process_env(run);
-                run_process(&cmd.unwrap(), &mut env)?;
+                run_process(&exe.unwrap(), &cmd, &mut env)?;
+                Ok(())
+            }
+            ActionType::List => {
+                run.list();
+                Ok(())
+            }
+            ActionType::Edit => edit_files(run),
+            ActionType::EditConfig => edit_config(run),
+            ActionType::Validate => {
+                // The login is cached once the user is authenticated, before this runs
+                let message = match config.security.cache_scope.resolve() {
+                    _ if run.cached => "Refreshed cached login",
+                    CacheScope::None => "Logins aren't cached, so there is nothing to refresh",
+                    _ => "No login was needed, so no cached login was refreshed",
+                };
+                output::info(message, config.display.nerd, None);
+                Ok(())
+            }
+            ActionType::CacheStatus => {
+                let scope = config.security.cache_scope.resolve();
+                let message = match cache::cache_remaining(run, config)? {
+                    Some(r) => format!(
+                        "Logged in as \"{}\" for another {} ({scope} scope)",
+                        run.do_as.name,
+                        duration::format(r)
+                    ),
+                    None => format!("Not logged in as \"{}\" ({scope} scope)", run.do_as.name),
+                };
+                output::info(message, config.display.nerd, Some(Output::Stdout));
+                Ok(())
+            }
+            ActionType::ClearUserCache => {
+                let name = run.cache_user.clone().unwrap_or_default();
+                if !run.user.uid.is_root() {
+                    bail!("Only root can clear the cache of other users");
+                }
+                let Some(user) = get_user(&name, run.backend.as_ref()) else {
+                    bail!("Couldn't find user \"{name}\"");
+                };
+                cache::clear_cache(&user, run.backend.as_ref())?;
+                output::info(
+                    format!("Cleared cache for user \"{}\"", user.name),
+                    config.display.nerd,
+                    None,
+                );
+                Ok(())
+            }
+            ActionType::ClearAllCaches => {
+                if !run.user.uid.is_root() {
+                    bail!("Only root can clear the cache of other users");
+                }
+                let cleared = cache::clear_all_caches(run.backend.as_ref())?;
+                output::info(
+                    format!("Cleared cache for {cleared} users"),
+                    config.display.nerd,
+                    None,
+                );
                 Ok(())
             }
         }
@@ -144,7 +235,10 @@ pub enum Flag {
 #[derive(Debug, Clone)]
 pub enum ErrorKind {
     NoUser,
+    NoGroup,
     IncorrectExePerms,
+    CommandNotFound,
+    InvalidEditFile,
 }
 
 #[derive(Debug, Clone)]
@@ -175,8 +269,22 @@ pub struct Run<'a> {
     pub actions: Vec<Action>,
     pub flags: HashSet<Flag>,
     pub command: Option<Vec<String>>,
+    /// The canonical path of `command[0]`, resolved against the safe path
+    pub executable: Option<PathBuf>,
+    /// The path `command[0]` was found at, before resolving it if it's a symlink
+    pub command_path: Option<PathBuf>,
+    /// The files to edit with udoedit, as absolute paths
+    pub edit_files: Option<Vec<PathBuf>>,
+    /// The user whose cache is cleared with `--clear-user`
+    pub cache_user: Option<String>,
     pub user: User,
     pub do_as: User,
+    /// The primary group to run as, if one was given
+    pub group: Option<Group>,
+    /// The environment policy of the rule authorising the run
+    pub env_policy: EnvPolicy,
+    /// Whether logging in wrote a cache entry, which it doesn't for nopass rules
+    pub cached: bool,
     pub config: &'a Config,
 }
 
@@ -185,19 +293,52 @@ impl<'a> Run<'a> {
         let do_as_arg = matches
             .get_one::<String>("user")
             .expect("No user specified. This should not happen! Please file a bug report");
-        let do_as = match get_user(do_as_arg) {
+        // The backend targets the user being run as, so it can't exist before they're looked up
+        let lookup = SystemBackend::default();
+        let do_as = match get_user(do_as_arg, &lookup) {
             Some(u) => u,
             None => return Err(Error::new(ErrorKind::NoUser, "Couldn't get target user")),
         };
 
-        let user = get_user_by_id(getuid())
+        let group = match matches.get_one::<String>("group") {
+            Some(g) => match get_group(g, &lookup) {
+                Some(g) => Some(g),
+                None => return Err(Error::new(ErrorKind::NoGroup, "Couldn't get target group")),
+            },
+            None => None,
+        };
+
+        let user = get_user_by_id(getuid(), &lookup)
             .expect("Cannot get current user. This should not happen! Please file a bug report");
 
         let mut actions = Self::get_actions(matches);
         let flags = Self::get_flags(matches);
         let mut command = None;
 
-        if let Some(cmd) = matches.get_many::<String>("command") {
+        let mut edit_files = None;
+
+        if matches.get_flag("edit_config") {
+            match resolve_edit_path(Path::new(CONFIG_PATH)) {
+                Ok(f) => edit_files = Some(vec![f]),
+                Err(e) => return Err(Error::new(ErrorKind::InvalidEditFile, e)),
+            }
+            actions.push(Action::new(ActionType::EditConfig, ActionReqs::auth()));
+        } else if matches.get_flag("edit") || is_udoedit() {
+            let mut files = Vec::new();
+            for file in matches.get_many::<String>("command").into_iter().flatten() {
+                match resolve_edit_path(Path::new(file)) {
+                    Ok(f) => files.push(f),
+                    Err(e) => return Err(Error::new(ErrorKind::InvalidEditFile, e)),
+                }
+            }
+            edit_files = Some(files);
+            actions.push(Action::new(ActionType::Edit, ActionReqs::auth()));
+        } else if matches.get_flag("list") {
+            command = matches
+                .get_many::<String>("command")
+                .map(|cmd| cmd.cloned().collect::<Vec<_>>());
+            actions.push(Action::new(ActionType::List, ActionReqs::default()));
+        } else if let Some(cmd) = matches.get_many::<String>("command") {
             command = Some(cmd.cloned().collect::<Vec<_>>());
             actions.push(Action::new(ActionType::RunCommand, ActionReqs::auth()));
         } else if matches.get_flag("login") {
@@ -206,15 +347,49 @@ impl<'a> Run<'a> {
             command = Some(vec![user.shell.to_string_lossy().to_string()])
         }
 
-        let backend = Box::new(SystemBackend::new(do_as.uid));
+        let gid = group.as_ref().map(|g| g.gid).unwrap_or(do_as.gid);
+        let backend = Box::new(SystemBackend::new(&do_as, gid));
+
+        // Resolve the command before authorising it, so that what is authorised is exactly what
+        // is executed
+        let mut executable = None;
+        let mut command_path = None;
+        if let Some(cmd) = &command {
+            // The caller's PATH isn't trusted, so commands are found the same way as in rules
+            let path = config
+                .security
+                .safe_path
+                .as_deref()
+                .unwrap_or(DEFAULT_SAFE_PATH);
+            let located = locate_command(&cmd[0], path);
+            match located.as_ref().and_then(|p| fs::canonicalize(p).ok()) {
+                Some(p) => {
+                    executable = Some(p);
+                    command_path = located;
+                }
+                None => {
+                    return Err(Error::new(
+                        ErrorKind::CommandNotFound,
+                        format!("Couldn't find command \"{}\"", cmd[0]),
+                    ));
+                }
+            }
+        }
 
         Ok(Self {
             backend,
             command,
+            executable,
+            command_path,
+            edit_files,
+            cache_user: matches.get_one::<String>("clear_user").cloned(),
             do_as,
+            group,
             user,
             actions,
             flags,
+            env_policy: EnvPolicy::default(),
+            cached: false,
             config,
         })
     }
@@ -224,11 +399,41 @@ impl<'a> Run<'a> {
         self
     }
 
+    /// Gets the arguments the command is run with. The first is the path the command was
+    /// authorised at, as multi-call binaries like busybox decide what to do based on it
+    pub fn argv(&self) -> Vec<String> {
+        let mut argv = self.command.clone().unwrap_or_default();
+        if let (Some(first), Some(path)) = (argv.first_mut(), &self.command_path) {
+            *first = path.to_string_lossy().to_string();
+        }
+        argv
+    }
+
     fn get_actions(matches: &ArgMatches) -> Vec<Action> {
         let mut ret = Vec::new();
         if matches.get_flag("clear") {
             ret.push(Action::new(ActionType::ClearCache, ActionReqs::auth()));
         }
+        if matches.get_flag("validate") {
+            ret.push(Action::new(ActionType::Validate, ActionReqs::auth()));
+        }
+        // Neither of these need a login, as only the cache is read, and only root can clear the
+        // cache of other users
+        if matches.get_flag("cache_status") {
+            ret.push(Action::new(ActionType::CacheStatus, ActionReqs::default()));
+        }
+        if matches.contains_id("clear_user") {
+            ret.push(Action::new(
+                ActionType::ClearUserCache,
+                ActionReqs::default(),
+            ));
+        }
+        if matches.get_flag("clear_all") {
+            ret.push(Action::new(
+                ActionType::ClearAllCaches,
+                ActionReqs::default(),
+            ));
+        }
         if matches.get_flag("login") {
             ret.push(Action::new(ActionType::Login, ActionReqs::auth()));
         }
@@ -261,10 +466,6 @@ impl<'a> Run<'a> {
             exit(1);
         }
 
-        if self.flags.contains(&Flag::Preview) {
-            self.preview();
-        }
-
         // Actions which require the user logs in
         let requires_login = actions
             .iter()
@@ -285,19 +486,59 @@ impl<'a> Run<'a> {
             .filter(|a| !requires_root.contains(a) && !requires_login.contains(a))
             .collect::<Vec<_>>();
 
+        for action in rest {
+            action.do_action(self, self.config)?;
+        }
+        if requires_login.is_empty() {
+            return Ok(());
+        }
+
+        // Decision represents if the user is actually allowed to do what they're trying to do.
+        // Only running a command or editing files is governed by the rules in the config
+        let decision = self.decide();
+
+        if self.flags.contains(&Flag::Preview) {
+            self.preview(decision.as_ref())?;
+        }
+
+        // Authorisation is determined before logging in, so that the user isn't prompted for a
+        // password for something they can't do, and so rules can skip the prompt entirely
+        if let Some(d) = &decision
+            && !d.allowed()
+        {
+            output::info(
+                format!("udo configuration does not authorise you to perform this action ({d})"),
+                self.config.display.nerd,
+                None,
+            );
+            return Ok(());
+        }
+        if let Some(d) = &decision {
+            self.env_policy = d.env_policy();
+        }
+        if self.flags.contains(&Flag::PreserveVars) && !self.env_policy.allow_preserve_env {
+            output::info(
+                "udo configuration does not allow you to preserve the environment",
+                self.config.display.nerd,
+                None,
+            );
+            return Ok(());
+        }
+        let nopass = decision.as_ref().is_some_and(Decision::nopass);
+
         // Authenticated represents if the user sucessfully logged in
-        let authenticated = self.login_user(self.config.security.tries);
-        // Authorised represents if the user is actually allowed to do what they're trying to do
-        let authorised = check_action_auth(self, self.config);
+        let authenticated = match nopass {
+            true => Ok(true),
+            false => self.login_user(self.config.security.tries),
+        };
         match authenticated {
-            Ok(true) => match authorised {
-                true => self.after_auth(requires_login, requires_root)?,
-                false => output::info(
-                    "udo configuration does not authorise you to perform this action",
-                    self.config.display.nerd,
-                    None,
-                ),
-            },
+            Ok(true) => {
+                // Running a nopass command mustn't create a login other commands can use
+                if !nopass {
+                    self.cached = self.write_cache()?;
+                }
+                self.after_auth(requires_login, requires_root)?
+            }
             Ok(false) => output::info("Login failed", self.config.display.nerd, None),
             Err(e) => output::error_with_details(
                 "Error while logging in",
@@ -310,6 +551,33 @@ impl<'a> Run<'a> {
         Ok(())
     }
 
+    /// Checks the run against the rules in the config. Editing files is only allowed if every
+    /// file may be edited, and only skips authentication if every file may be without it.
+    /// Refreshing a login is only allowed if some rule allows the user to do something
+    fn decide(&self) -> Option<Decision> {
+        if let Some(files) = &self.edit_files {
+            let decisions = files
+                .iter()
+                .map(|f| check_edit_auth(self, self.config, f))
+                .collect::<Vec<_>>();
+            return decisions
+                .iter()
+                .find(|d| !d.allowed())
+                .or_else(|| decisions.iter().find(|d| !d.nopass()))
+                .or(decisions.last())
+                .cloned();
+        }
+
+        if self.command.is_some() {
+            return Some(check_action_auth(self, self.config));
+        }
+
+        self.actions
+            .iter()
+            .any(|a| a.a_type == ActionType::Validate)
+            .then(|| check_validate_auth(self, self.config))
+    }
+
     fn login_user(&mut self, tries: usize) -> anyhow::Result<bool> {
         match check_cache(self, self.config) {
             Ok(true) => return Ok(true),
@@ -352,10 +620,24 @@ impl<'a> Run<'a> {
         }
     }
 
-    fn after_auth(&mut self, login: Vec<Action>, root: Vec<Action>) -> anyhow::Result<()> {
+    /// Caches the login of the user, returning whether an entry was written
+    fn write_cache(&mut self) -> anyhow::Result<bool> {
+        let entry = CacheEntry::try_from(&*self)?;
+        if entry.scope() == CacheScope::None {
+            return Ok(false);
+        }
         cache::create_cache_dir(&self.user, self.backend.as_ref())?;
-        let entry: CacheEntry = self.try_into()?;
-        cache::write_entry(&self.user, entry, self.backend.as_ref())?;
+        cache::write_entry(
+            &self.user,
+            entry,
+            self.config.security.timeout,
+            self.backend.as_ref(),
+        )?;
+
+        Ok(true)
+    }
+
+    fn after_auth(&mut self, login: Vec<Action>, root: Vec<Action>) -> anyhow::Result<()> {
         for action in login {
             let res = action.do_action(self, self.config);
 
@@ -371,7 +653,57 @@ impl<'a> Run<'a> {
         Ok(())
     }
 
-    fn preview(&self) {
+    /// Lists the rules which apply to the user, or if a command was given, whether the user may
+    /// run it. Exits with an error if they may not
+    fn list(&self) {
+        let nerd = self.config.display.nerd;
+        if let Some(cmd) = &self.command {
+            let decision = check_action_auth(self, self.config);
+            output::info(
+                format!("\"{}\" is {decision}", cmd.join(" ")),
+                nerd,
+                Some(Output::Stdout),
+            );
+            if !decision.allowed() {
+                exit(1);
+            }
+            return;
+        }
+
+        let rules = match get_matching_rules(&self.user, self.config, self.backend.as_ref()) {
+            Ok(r) => r,
+            Err(e) => {
+                output::error(
+                    format!("Couldn't check the rules which apply to you ({e})"),
+                    nerd,
+                    None,
+                );
+                exit(1);
+            }
+        };
+        if rules.is_empty() {
+            output::info(
+                format!("No rules apply to user \"{}\"", self.user.name),
+                nerd,
+                Some(Output::Stdout),
+            );
+            return;
+        }
+
+        output::info(
+            format!("Rules which apply to user \"{}\"", self.user.name),
+            nerd,
+            Some(Output::Stdout),
+        );
+        for (i, rule) in rules {
+            let line = MultiStyled::default()
+                .with(format!("{}: ", rule.position(i)).stylize().bold())
+                .with(rule.to_string().stylize().italic());
+            println!("{line}");
+        }
+    }
+
+    fn preview(&self, decision: Option<&Decision>) -> anyhow::Result<()> {
         output::info(
             "udo will perform the following actions",
             self.config.display.nerd,
@@ -380,10 +712,16 @@ impl<'a> Run<'a> {
 
         let mut tty = Output::Tty.get_write();
 
-        self.actions.iter().for_each(|a| {
+        self.actions.iter().try_for_each(|a| {
             let output = format!("{}\n", self.display_action(a));
-            execute!(tty, Print(output));
-        });
+            execute!(tty, Print(output))
+        })?;
+        if let Some(decision) = decision {
+            let decision = MultiStyled::default()
+                .with("authorisation: ".to_string().stylize().bold())
+                .with(decision.to_string().stylize().italic());
+            execute!(tty, Print(format!("{decision}\n")))?;
+        }
         enable_raw_mode().unwrap();
         let yes = output::confirm::Confirmation::default()
             .with_prompt("Continue?")
@@ -394,6 +732,8 @@ impl<'a> Run<'a> {
         if !yes {
             std::process::exit(0)
         }
+
+        Ok(())
     }
 
     fn display_action(&self, action: &Action) -> MultiStyled<String> {
@@ -413,6 +753,20 @@ impl<'a> Run<'a> {
                     None
                 }
             }
+            ActionType::List => self.command.as_ref().map(|cmd| cmd.join(" ")),
+            ActionType::EditConfig => Some(CONFIG_PATH.to_string()),
+            ActionType::Validate | ActionType::CacheStatus => {
+                Some(format!("of user {}", self.user.name))
+            }
+            ActionType::ClearUserCache => self.cache_user.as_ref().map(|u| format!("of user {u}")),
+            ActionType::ClearAllCaches => None,
+            ActionType::Edit => self.edit_files.as_ref().map(|files| {
+                files
+                    .iter()
+                    .map(|f| f.to_string_lossy())
+                    .collect::<Vec<_>>()
+                    .join(" ")
+            }),
         };
 
         if let Some(info) = info {
@@ -0,0 +1,444 @@
+use std::{
+    fs::{self, File, OpenOptions, Permissions},
+    io::{self, Read, Write},
+    os::unix::{
+        fs::{MetadataExt, OpenOptionsExt, PermissionsExt, fchown},
+        process::CommandExt,
+    },
+    path::{Path, PathBuf},
+    process::{Command, exit},
+};
+
+use anyhow::{Result, anyhow, bail};
+use crossterm::terminal::{disable_raw_mode, enable_raw_mode};
+use nix::{
+    libc,
+    sys::wait::{WaitStatus, waitpid},
+    unistd::{AccessFlags, ForkResult, access, fork, mkdtemp, pipe},
+};
+use xattr::FileExt;
+
+use crate::{
+    config::{
+        CONFIG_PATH,
+        check::{check_config, has_errors, print_diagnostics},
+    },
+    output::{self, confirm::Confirmation},
+    run::Run,
+};
+
+/// Exit code used by the child reading a file to signal that it doesn't exist yet
+const NEW_FILE: i32 = 2;
+
+/// A file being edited, along with the temporary copy the editor is run on
+struct EditFile {
+    path: PathBuf,
+    temp: PathBuf,
+    /// The original content, or [None] if the file is being created
+    original: Option<Vec<u8>>,
+}
+
+/// Resolves a file to edit to an absolute path, without following a symlink at the file itself.
+///
+/// Like sudoedit, files in directories the invoking user can write to are refused, as they can
+/// edit those without udo and they could swap the file out from under us.
+pub fn resolve_edit_path(path: &Path) -> std::result::Result<PathBuf, String> {
+    let name = path
+        .file_name()
+        .ok_or(format!("{} is not a file", path.display()))?;
+    let parent = match path.parent() {
+        Some(p) if !p.as_os_str().is_empty() => p,
+        _ => Path::new("."),
+    };
+    let parent = fs::canonicalize(parent)
+        .map_err(|e| format!("Couldn't resolve {} ({e})", parent.display()))?;
+
+    // access checks against the real uid, which is always the invoking user
+    if access(&parent, AccessFlags::W_OK).is_ok() {
+        return Err(format!(
+            "You can already write to {}, edit {} directly",
+            parent.display(),
+            path.display()
+        ));
+    }
+
+    Ok(parent.join(name))
+}
+
+/// Edits files as the target user, like sudoedit.
+///
+/// Each file is read as the target user and copied into a temporary directory owned by the
+/// invoking user. The editor is run as the invoking user on the copies, and any changed copies
+/// are written back atomically as the target user.
+pub fn edit_files(run: &mut Run) -> Result<()> {
+    let paths = run.edit_files.clone().unwrap_or_default();
+
+    // The temporary directory is created as the invoking user, so they own the copies
+    run.backend.restore()?;
+    let dir = mkdtemp(&std::env::temp_dir().join("udoedit.XXXXXX"))?;
+    let res = edit_in(run, &paths, &dir);
+    let _ = fs::remove_dir_all(&dir);
+
+    res
+}
+
+fn edit_in(run: &Run, paths: &[PathBuf], dir: &Path) -> Result<()> {
+    let mut files = Vec::new();
+    for (i, path) in paths.iter().enumerate() {
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        let temp = dir.join(format!("{i}-{name}"));
+        let original = read_as_target(run, path)?;
+
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(&temp)?;
+        file.write_all(original.as_deref().unwrap_or_default())?;
+
+        files.push(EditFile {
+            path: path.clone(),
+            temp,
+            original,
+        });
+    }
+
+    run_editor(
+        run,
+        &files.iter().map(|f| f.temp.as_path()).collect::<Vec<_>>(),
+    )?;
+
+    for file in files {
+        let edited = fs::read(&file.temp)?;
+        let unchanged = match &file.original {
+            Some(o) => *o == edited,
+            // Like sudoedit, new files left empty aren't created
+            None => edited.is_empty(),
+        };
+
+        if unchanged {
+            output::info(
+                format!("{} unchanged", file.path.display()),
+                run.config.display.nerd,
+                None,
+            );
+        } else {
+            write_as_target(run, &file.path, &edited)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Edits the installed config like [edit_files], like visudo. The edited config is only installed
+/// if it has no errors, otherwise the user can edit it again or discard it.
+pub fn edit_config(run: &mut Run) -> Result<()> {
+    run.backend.restore()?;
+    let dir = mkdtemp(&std::env::temp_dir().join("udoedit.XXXXXX"))?;
+    let res = edit_config_in(run, &dir);
+    let _ = fs::remove_dir_all(&dir);
+
+    res
+}
+
+fn edit_config_in(run: &Run, dir: &Path) -> Result<()> {
+    let path = Path::new(CONFIG_PATH);
+    let nerd = run.config.display.nerd;
+    let temp = dir.join("config.toml");
+    let original = read_as_target(run, path)?.unwrap_or_default();
+
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(&temp)?;
+    file.write_all(&original)?;
+
+    loop {
+        run_editor(run, &[temp.as_path()])?;
+        let edited = fs::read(&temp)?;
+        if edited == original {
+            output::info(format!("{} unchanged", path.display()), nerd, None);
+            return Ok(());
+        }
+
+        let diagnostics = check_config(&String::from_utf8_lossy(&edited), run.backend.as_ref());
+        print_diagnostics(path, &diagnostics, nerd);
+        if !has_errors(&diagnostics) {
+            // The config is replaced atomically, so failing to write it leaves the old config in
+            // place rather than a partial one which locks everyone out
+            write_as_target(run, path, &edited)?;
+            output::info(format!("Installed {}", path.display()), nerd, None);
+            return Ok(());
+        }
+
+        enable_raw_mode()?;
+        let again = Confirmation::default()
+            .with_prompt("The config has errors. Edit it again?")
+            .run()
+            .unwrap_or_default();
+        disable_raw_mode()?;
+
+        if !again {
+            output::info(format!("{} unchanged", path.display()), nerd, None);
+            return Ok(());
+        }
+    }
+}
+
+/// Runs the invoking user's editor on the copies, as the invoking user
+fn run_editor(run: &Run, files: &[&Path]) -> Result<()> {
+    let editor = ["VISUAL", "EDITOR"]
+        .iter()
+        .filter_map(|v| run.backend.get_var(v).ok())
+        .find(|e| !e.is_empty())
+        .unwrap_or("vi".to_string());
+
+    // We need to be root for the editor to drop privileges completely, including the saved uid
+    run.backend.elevate()?;
+    // The editor is run through the shell so it can contain arguments, e.g. `code --wait`
+    let status = Command::new("/bin/sh")
+        .arg("-c")
+        .arg(format!("{editor} \"$@\""))
+        .arg(&editor)
+        .args(files)
+        .uid(run.user.uid.as_raw())
+        .gid(run.user.gid.as_raw())
+        .status();
+    run.backend.restore()?;
+
+    if !status?.success() {
+        bail!("Editor exited with an error, not saving changes");
+    }
+
+    Ok(())
+}
+
+/// Reads a file as the target user in a child process, returning [None] if it doesn't exist
+fn read_as_target(run: &Run, path: &Path) -> Result<Option<Vec<u8>>> {
+    let (read, write) = pipe()?;
+
+    match unsafe { fork()? } {
+        ForkResult::Child => {
+            drop(read);
+            let code = match target_read(run, path, File::from(write)) {
+                Ok(true) => 0,
+                Ok(false) => NEW_FILE,
+                Err(_) => 1,
+            };
+            exit(code)
+        }
+        ForkResult::Parent { child } => {
+            drop(write);
+            let mut content = Vec::new();
+            File::from(read).read_to_end(&mut content)?;
+
+            match waitpid(child, None)? {
+                WaitStatus::Exited(_, 0) => Ok(Some(content)),
+                WaitStatus::Exited(_, NEW_FILE) => Ok(None),
+                _ => bail!("Couldn't read {} as {}", path.display(), run.do_as.name),
+            }
+        }
+    }
+}
+
+fn target_read(run: &Run, path: &Path, mut pipe: File) -> Result<bool> {
+    run.backend.switch_final()?;
+
+    let file = OpenOptions::new()
+        .read(true)
+        .custom_flags(libc::O_NOFOLLOW)
+        .open(path);
+    match file {
+        Ok(mut f) => {
+            if !f.metadata()?.is_file() {
+                bail!("{} is not a regular file", path.display());
+            }
+            io::copy(&mut f, &mut pipe)?;
+            Ok(true)
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(false),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Writes a file as the target user in a child process
+fn write_as_target(run: &Run, path: &Path, content: &[u8]) -> Result<()> {
+    let (read, write) = pipe()?;
+
+    match unsafe { fork()? } {
+        ForkResult::Child => {
+            drop(write);
+            let code = match target_write(run, path, File::from(read)) {
+                Ok(_) => 0,
+                Err(_) => 1,
+            };
+            exit(code)
+        }
+        ForkResult::Parent { child } => {
+            drop(read);
+            // If the child failed early the pipe is closed, which waitpid will report
+            let _ = File::from(write).write_all(content);
+
+            match waitpid(child, None)? {
+                WaitStatus::Exited(_, 0) => Ok(()),
+                _ => bail!("Couldn't write {} as {}", path.display(), run.do_as.name),
+            }
+        }
+    }
+}
+
+/// Writes the file as the target user. The new content is written to a temporary file next to it,
+/// which is renamed over the file, so an interrupted write never leaves a partial file behind
+fn target_write(run: &Run, path: &Path, mut pipe: File) -> Result<()> {
+    run.backend.switch_final()?;
+
+    let mut content = Vec::new();
+    pipe.read_to_end(&mut content)?;
+
+    replace_file(path, &content)
+}
+
+/// Atomically replaces the file at `path` with `content`. The replacement is given the owner,
+/// group, permissions and extended attributes (including ACLs) of the file it replaces, and fails
+/// if it can't keep them. Files which don't exist yet are created with the mode 0644
+fn replace_file(path: &Path, content: &[u8]) -> Result<()> {
+    // O_NONBLOCK stops opening a FIFO from blocking before we can check it's a regular file
+    let original = OpenOptions::new()
+        .read(true)
+        .custom_flags(libc::O_NOFOLLOW | libc::O_NONBLOCK)
+        .open(path);
+    let original = match original {
+        Ok(f) if f.metadata()?.is_file() => Some(f),
+        Ok(_) => bail!("{} is not a regular file", path.display()),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+        Err(e) => return Err(e.into()),
+    };
+
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    let temp = path.with_file_name(format!(".{name}.udo-{}", std::process::id()));
+    let res = write_replacement(&temp, original.as_ref(), content).and_then(|_| match original {
+        Some(_) => Ok(fs::rename(&temp, path)?),
+        // Linking fails if the file exists, so a file created in the meantime isn't replaced,
+        // as we didn't read it
+        None => Ok(fs::hard_link(&temp, path)?),
+    });
+    let _ = fs::remove_file(&temp);
+
+    res
+}
+
+fn write_replacement(temp: &Path, original: Option<&File>, content: &[u8]) -> Result<()> {
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .custom_flags(libc::O_NOFOLLOW)
+        .mode(0o600)
+        .open(temp)?;
+    file.write_all(content)?;
+
+    match original {
+        Some(original) => {
+            let metadata = original.metadata()?;
+            fchown(&file, Some(metadata.uid()), Some(metadata.gid()))
+                .map_err(|e| anyhow!("Couldn't keep the owner and group of the file ({e})"))?;
+            copy_xattrs(original, &file)?;
+            // Changing the owner clears the setuid and setgid bits, so the mode is set last
+            file.set_permissions(metadata.permissions())?;
+        }
+        None => file.set_permissions(Permissions::from_mode(0o644))?,
+    }
+
+    file.sync_all()?;
+    Ok(())
+}
+
+/// Copies the extended attributes of `from` to `to`, doing nothing if the filesystem doesn't
+/// support them
+fn copy_xattrs(from: &File, to: &File) -> Result<()> {
+    let names = match from.list_xattr() {
+        Ok(n) => n,
+        Err(e) if e.raw_os_error() == Some(libc::ENOTSUP) => return Ok(()),
+        Err(e) => return Err(e.into()),
+    };
+
+    for name in names {
+        if let Some(value) = from.get_xattr(&name)? {
+            to.set_xattr(&name, &value).map_err(|e| {
+                anyhow!(
+                    "Couldn't keep the attribute {} of the file ({e})",
+                    name.to_string_lossy()
+                )
+            })?;
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        fs::{self, Permissions},
+        os::unix::fs::{PermissionsExt, symlink},
+        path::Path,
+    };
+
+    use nix::unistd::mkdtemp;
+
+    use crate::run::edit::replace_file;
+
+    fn mode(path: &Path) -> u32 {
+        fs::metadata(path).unwrap().permissions().mode() & 0o7777
+    }
+
+    #[test]
+    fn replaces_files() {
+        let dir = mkdtemp(&std::env::temp_dir().join("udo-test.XXXXXX")).unwrap();
+        let file = dir.join("file");
+        fs::write(&file, "old").unwrap();
+        fs::set_permissions(&file, Permissions::from_mode(0o640)).unwrap();
+
+        replace_file(&file, b"new").unwrap();
+        assert_eq!(fs::read_to_string(&file).unwrap(), "new");
+        assert_eq!(mode(&file), 0o640);
+
+        // New files are created readable by everyone
+        let new = dir.join("new");
+        replace_file(&new, b"created").unwrap();
+        assert_eq!(fs::read_to_string(&new).unwrap(), "created");
+        assert_eq!(mode(&new), 0o644);
+
+        // Symlinks aren't followed, so they can't redirect the write
+        let link = dir.join("link");
+        symlink(&file, &link).unwrap();
+        assert!(replace_file(&link, b"redirected").is_err());
+        assert_eq!(fs::read_to_string(&file).unwrap(), "new");
+        assert!(replace_file(&dir, b"").is_err());
+
+        // No temporary files are left behind
+        let mut names = fs::read_dir(&dir)
+            .unwrap()
+            .map(|e| e.unwrap().file_name())
+            .collect::<Vec<_>>();
+        names.sort();
+        assert_eq!(names, ["file", "link", "new"]);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn failed_replace_keeps_original() {
+        let dir = mkdtemp(&std::env::temp_dir().join("udo-test.XXXXXX")).unwrap();
+        let config = dir.join("config.toml");
+        fs::write(&config, "[[rules]]").unwrap();
+
+        // Occupying the path of the temporary file makes the write fail before the rename
+        let temp = dir.join(format!(".config.toml.udo-{}", std::process::id()));
+        fs::create_dir(&temp).unwrap();
+        assert!(replace_file(&config, b"[[rul").is_err());
+        assert_eq!(fs::read_to_string(&config).unwrap(), "[[rules]]");
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
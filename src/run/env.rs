@@ -1,14 +1,28 @@
-use std::env;
+use std::{collections::BTreeMap, env};
 
 use nix::sys::stat::{Mode, umask};
 
 use anyhow::Result;
+use serde::{Deserialize, Serialize};
 
 use crate::{
     backend::Backend,
     run::{Flag, Run},
 };
 
+/// EnvPolicy is the environment policy of a [Rule](crate::authenticate::Rule), mirroring doas's
+/// `keepenv` and `setenv`
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+#[serde(default)]
+pub struct EnvPolicy {
+    /// Variables preserved in addition to the safe variables
+    pub keepenv: Vec<String>,
+    /// Variables set in the environment of the command
+    pub setenv: BTreeMap<String, String>,
+    /// Whether the environment may be preserved with `--preserve-env`
+    pub allow_preserve_env: bool,
+}
+
 pub struct Vars {
     pub home: String,
     pub user: String,
@@ -42,8 +56,13 @@ impl Vars {
 pub struct Env<'a> {
     pub login: bool,
     pub preserve_all: bool,
+    /// Names or wildcard patterns of variables which are preserved
     pub safe_vars: Vec<String>,
+    /// Names or wildcard patterns of variables which are always removed, even with `preserve_all`
+    pub delete_vars: Vec<String>,
     pub set_vars: Vars,
+    /// Variables set by the rule authorising the run, applied last
+    pub rule_vars: BTreeMap<String, String>,
     pub backend: &'a mut Box<dyn Backend>,
 }
 
@@ -59,44 +78,81 @@ impl<'a> Env<'a> {
     ];
 
     // These vars are always preserved
-    const PRESERVE_VARS: [&'a str; 2] = ["TERM", "DISPLAY"];
+    const PRESERVE_VARS: [&'a str; 3] = ["TERM", "DISPLAY", "LC_*"];
+
+    // These vars can change how the command runs, so they're always removed
+    const DELETE_VARS: [&'a str; 6] = ["LD_*", "DYLD_*", "PYTHON*", "PERL5*", "BASH_ENV", "IFS"];
 
     pub fn login_env(run: &'a mut Run) -> Self {
-        let safe_vars = Self::const_vars_to_vec(&Self::PRESERVE_VARS);
         Self {
             login: true,
-            safe_vars,
-            preserve_all: run.flags.contains(&Flag::PreserveVars),
+            safe_vars: Self::safe_vars(run, true),
+            delete_vars: Self::delete_vars(run),
+            preserve_all: Self::preserve_all(run),
             set_vars: Vars::login(run),
+            rule_vars: run.env_policy.setenv.clone(),
             backend: &mut run.backend,
         }
     }
 
     pub fn non_login_env(run: &'a mut Run) -> Self {
-        let mut safe_vars = Self::const_vars_to_vec(&Self::SAFE_VARS);
-        safe_vars.append(&mut Self::const_vars_to_vec(&Self::PRESERVE_VARS));
-
         Self {
             login: false,
-            safe_vars,
-            preserve_all: run.flags.contains(&Flag::PreserveVars),
+            safe_vars: Self::safe_vars(run, false),
+            delete_vars: Self::delete_vars(run),
+            preserve_all: Self::preserve_all(run),
             set_vars: Vars::non_login(run),
+            rule_vars: run.env_policy.setenv.clone(),
             backend: &mut run.backend,
         }
     }
 
     pub fn process_env(run: &'a mut Run) -> Self {
-        let mut safe_vars = Self::const_vars_to_vec(&Self::SAFE_VARS);
-        safe_vars.append(&mut Self::const_vars_to_vec(&Self::PRESERVE_VARS));
         Self {
             login: false,
-            safe_vars,
-            preserve_all: run.flags.contains(&Flag::PreserveVars),
+            safe_vars: Self::safe_vars(run, false),
+            delete_vars: Self::delete_vars(run),
+            preserve_all: Self::preserve_all(run),
             set_vars: Vars::non_login(run),
+            rule_vars: run.env_policy.setenv.clone(),
             backend: &mut run.backend,
         }
     }
 
+    /// Collects the variables to preserve from the defaults, the security config, and the rule
+    /// authorising the run. Login environments only preserve the always preserved variables.
+    fn safe_vars(run: &Run, login: bool) -> Vec<String> {
+        let security = &run.config.security;
+        let mut safe_vars = Vec::new();
+
+        if !security.env_replace_defaults {
+            safe_vars.append(&mut Self::const_vars_to_vec(&Self::PRESERVE_VARS));
+            if !login {
+                safe_vars.append(&mut Self::const_vars_to_vec(&Self::SAFE_VARS));
+            }
+        }
+
+        safe_vars.extend(security.env_always_keep.iter().cloned());
+        if !login {
+            safe_vars.extend(security.env_keep.iter().cloned());
+        }
+        safe_vars.extend(run.env_policy.keepenv.iter().cloned());
+
+        safe_vars
+    }
+
+    fn delete_vars(run: &Run) -> Vec<String> {
+        let mut delete_vars = Self::const_vars_to_vec(&Self::DELETE_VARS);
+        delete_vars.extend(run.config.security.env_delete.iter().cloned());
+        delete_vars
+    }
+
+    /// The environment is only preserved if it was requested and the rule authorising the run
+    /// allows it
+    fn preserve_all(run: &Run) -> bool {
+        run.flags.contains(&Flag::PreserveVars) && run.env_policy.allow_preserve_env
+    }
+
     pub unsafe fn apply(&mut self) -> Result<()> {
         unsafe {
             umask(Mode::from_bits_truncate(0o022));
@@ -115,13 +171,13 @@ impl<'a> Env<'a> {
         let vars = self.backend.vars();
 
         unsafe {
-            if !self.preserve_all {
-                for (var, _) in vars {
-                    if !self.is_var_valid(&var) {
-                        self.backend.remove_var(&var);
-                    }
+            for (var, _) in vars {
+                if self.is_var_deleted(&var) || (!self.preserve_all && !self.is_var_valid(&var)) {
+                    self.backend.remove_var(&var);
                 }
+            }
 
+            if !self.preserve_all {
                 if let Some(p) = &self.set_vars.path {
                     self.backend.set_var("PATH", p);
                 }
@@ -130,16 +186,104 @@ impl<'a> Env<'a> {
                 self.backend.set_var("USER", &self.set_vars.user);
                 self.backend.set_var("LOGNAME", &self.set_vars.logname);
             }
+
+            for (var, value) in &self.rule_vars {
+                self.backend.set_var(var, value);
+            }
         }
     }
 
-    fn is_var_valid(&self, var: &String) -> bool {
-        self.safe_vars.contains(var)
-            || var.starts_with("LC_")
+    fn is_var_valid(&self, var: &str) -> bool {
+        self.safe_vars.iter().any(|p| Self::var_matches(p, var))
             || (self.set_vars.path.is_none() && var == "PATH")
     }
 
+    fn is_var_deleted(&self, var: &str) -> bool {
+        self.delete_vars.iter().any(|p| Self::var_matches(p, var))
+    }
+
+    /// Matches a variable name against a name or wildcard pattern like `LC_*`
+    fn var_matches(pattern: &str, var: &str) -> bool {
+        match glob::Pattern::new(pattern) {
+            Ok(p) => p.matches(var),
+            Err(_) => pattern == var,
+        }
+    }
+
     fn const_vars_to_vec(vars: &[&str]) -> Vec<String> {
         vars.iter().copied().map(str::to_string).collect()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use crate::{
+        backend::{Backend, testing::TestBackend},
+        run::env::{Env, Vars},
+    };
+
+    fn vars() -> Vars {
+        Vars {
+            home: "/root".into(),
+            user: "root".into(),
+            logname: "root".into(),
+            shell: "/bin/sh".into(),
+            path: Some("/usr/bin".into()),
+        }
+    }
+
+    #[test]
+    fn rule_vars() {
+        let mut backend: Box<dyn Backend> = Box::new(TestBackend::default());
+        unsafe {
+            backend.set_var("SSH_AUTH_SOCK", "/tmp/agent");
+            backend.set_var("LD_PRELOAD", "/tmp/evil.so");
+        }
+
+        let mut env = Env {
+            login: false,
+            preserve_all: false,
+            safe_vars: vec!["SSH_AUTH_SOCK".into()],
+            delete_vars: Env::const_vars_to_vec(&Env::DELETE_VARS),
+            set_vars: vars(),
+            rule_vars: BTreeMap::from([("FOO".into(), "bar".into())]),
+            backend: &mut backend,
+        };
+        unsafe { env.apply_vars() };
+
+        assert_eq!(backend.get_var("SSH_AUTH_SOCK").unwrap(), "/tmp/agent");
+        assert_eq!(backend.get_var("FOO").unwrap(), "bar");
+        assert!(backend.get_var("LD_PRELOAD").is_err());
+    }
+
+    #[test]
+    fn delete_vars_with_preserve_all() {
+        let mut backend: Box<dyn Backend> = Box::new(TestBackend::default());
+        unsafe {
+            backend.set_var("HTTP_PROXY", "http://proxy");
+            backend.set_var("LC_ALL", "C");
+            backend.set_var("LD_LIBRARY_PATH", "/tmp");
+            backend.set_var("PYTHONPATH", "/tmp");
+            backend.set_var("IFS", " ");
+        }
+
+        let mut env = Env {
+            login: false,
+            preserve_all: true,
+            safe_vars: Env::const_vars_to_vec(&Env::PRESERVE_VARS),
+            delete_vars: Env::const_vars_to_vec(&Env::DELETE_VARS),
+            set_vars: vars(),
+            rule_vars: BTreeMap::new(),
+            backend: &mut backend,
+        };
+        unsafe { env.apply_vars() };
+
+        assert!(backend.get_var("HTTP_PROXY").is_ok());
+        assert!(backend.get_var("LC_ALL").is_ok());
+        assert!(backend.get_var("LD_LIBRARY_PATH").is_err());
+        assert!(backend.get_var("PYTHONPATH").is_err());
+        assert!(backend.get_var("IFS").is_err());
+    }
+}
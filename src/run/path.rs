@@ -0,0 +1,91 @@
+use std::{
+    fs,
+    os::unix::fs::PermissionsExt,
+    path::{Path, PathBuf},
+};
+
+/// The PATH rule commands are resolved against if `security.safe_path` is unset
+pub const DEFAULT_SAFE_PATH: &str = "/usr/local/sbin:/usr/local/bin:/usr/sbin:/usr/bin:/sbin:/bin";
+
+/// Resolves a command to an absolute, canonical path.
+///
+/// Commands containing a `/` are canonicalized as is, otherwise each directory in `path` is
+/// searched in order for an executable with the command's name. Returns [None] if no executable
+/// can be found.
+#[cfg(test)]
+pub fn resolve_command(command: &str, path: &str) -> Option<PathBuf> {
+    locate_command(command, path).and_then(|p| fs::canonicalize(p).ok())
+}
+
+/// Finds a command like [resolve_command], but only resolves the directory it's in. If the
+/// command is a symlink, the path of the symlink is returned rather than the path it points to.
+pub fn locate_command(command: &str, path: &str) -> Option<PathBuf> {
+    if command.contains('/') {
+        return located_executable(Path::new(command));
+    }
+
+    path.split(':')
+        .filter(|dir| !dir.is_empty())
+        .map(|dir| Path::new(dir).join(command))
+        // Relative directories in PATH would make the result depend on the working directory
+        .filter(|candidate| candidate.is_absolute())
+        .find_map(|candidate| located_executable(&candidate))
+}
+
+fn located_executable(path: &Path) -> Option<PathBuf> {
+    let name = path.file_name()?;
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let path = fs::canonicalize(dir).ok()?.join(name);
+    // Symlinks are followed, so this checks the executable they point to
+    let md = fs::metadata(&path).ok()?;
+
+    if md.is_file() && md.permissions().mode() & 0o111 != 0 {
+        Some(path)
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use crate::run::path::{locate_command, resolve_command};
+
+    #[test]
+    fn resolves_from_path() {
+        assert_eq!(
+            resolve_command("sh", "relative/dir::/bin"),
+            std::fs::canonicalize("/bin/sh").ok()
+        );
+    }
+
+    #[test]
+    fn resolves_absolute() {
+        let sh = std::fs::canonicalize("/bin/sh").unwrap();
+        assert_eq!(resolve_command("/bin/sh", ""), Some(sh));
+    }
+
+    #[test]
+    fn locates_without_resolving_symlinks() {
+        let sh = locate_command("sh", "/bin").unwrap();
+        assert_eq!(sh.file_name(), Some("sh".as_ref()));
+        assert_eq!(sh.parent(), std::fs::canonicalize("/bin").ok().as_deref());
+        assert_eq!(
+            std::fs::canonicalize(&sh).ok(),
+            resolve_command("sh", "/bin")
+        );
+        assert_eq!(locate_command("/bin/sh", ""), Some(sh));
+        assert_eq!(locate_command("/bin/..", ""), None);
+        assert!(Path::new("/etc").exists() && locate_command("/etc", "").is_none());
+    }
+
+    #[test]
+    fn rejects_missing_and_non_files() {
+        assert_eq!(resolve_command("udo-does-not-exist", "/bin:/usr/bin"), None);
+        assert_eq!(resolve_command("/etc", "/"), None);
+    }
+}
@@ -1,4 +1,4 @@
-use std::{ffi::CString, process::exit};
+use std::{ffi::CString, path::Path, process::exit};
 
 use anyhow::Result;
 use nix::{
@@ -11,9 +11,11 @@ use nix::{
 
 use crate::run::env::Env;
 
-pub fn run_process<S: ToString>(cmd: &[S], env: &mut Env) -> Result<()> {
+/// Runs `cmd`, executing the already resolved `program` in place of `cmd[0]`
+pub fn run_process<S: ToString>(program: &Path, cmd: &[S], env: &mut Env) -> Result<()> {
     let cmd = cmd.iter().map(|s| s.to_string()).collect::<Vec<_>>();
-    let cmd_name = cmd[0].as_str();
+    let program = program.to_string_lossy();
+    let cmd_name = program.as_ref();
     let args = cmd.iter().map(String::as_str).collect::<Vec<_>>();
 
     run_with_args(cmd_name, &args, env)?;
@@ -25,8 +27,10 @@ pub fn run_with_args<S: ToString>(name: S, args: &[S], env: &mut Env) -> Result<
     let cmd_name = name.to_string();
     let mut args = args.iter().map(|s| s.to_string()).collect::<Vec<_>>();
 
+    // Login shells are run as e.g. `-bash`, without their directory
     if env.login {
-        args[0] = format!("-{}", args[0]);
+        let name = Path::new(&args[0]).file_name().unwrap_or_default();
+        args[0] = format!("-{}", name.to_string_lossy());
     }
 
     let args_str = args.iter().map(String::as_str).collect();